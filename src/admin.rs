@@ -0,0 +1,29 @@
+use std::collections::HashSet;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+/// Telegram users allowed to manage the bot.
+#[derive(Debug, Clone, Default)]
+pub struct Admins(HashSet<i64>);
+
+impl Admins {
+    pub fn contains(&self, user: i64) -> bool {
+        self.0.contains(&user)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl FromStr for Admins {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use teloxide::adaptors::AutoSend;
+use teloxide::requests::Requester;
+use teloxide::Bot;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::field::{Field, Visit};
+use tracing::{warn, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::i18n::Lang;
+use crate::shutdown::Shutdown;
+
+// Telegram rejects longer messages.
+const MAX_MESSAGE_CHARS: usize = 4096;
+const MAX_ALERT_CHARS: usize = 500;
+
+// Collects the message and fields of an event into a line.
+#[derive(Default)]
+struct Line(String);
+
+impl Visit for Line {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Forwards `error!` events to [`Alerts`].
+#[derive(Debug)]
+pub struct AlertLayer {
+    tx: UnboundedSender<String>,
+}
+
+impl<S: Subscriber> Layer<S> for AlertLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() != Level::ERROR {
+            return;
+        }
+        let mut line = Line::default();
+        event.record(&mut line);
+        // alerts are dropped once the sink is gone
+        let _ = self.tx.send(format!("{}: {}", metadata.target(), line.0));
+    }
+}
+
+// Suppresses alerts already sent within the window, counting them for the next one sent.
+#[derive(Debug, Default)]
+struct Dedup {
+    sent: HashMap<String, (Instant, u64)>,
+}
+
+impl Dedup {
+    // Admit `count` occurrences of `alert` unless it was sent within the window.
+    // Admitted alerts come with the occurrences suppressed since the last one sent.
+    fn admit(
+        &mut self,
+        alert: &str,
+        count: u64,
+        now: Instant,
+        window: Duration,
+    ) -> Option<(u64, u64)> {
+        // expired alerts are kept until their suppressed occurrences are reported
+        self.sent.retain(|_, (sent_at, suppressed)| {
+            now.duration_since(*sent_at) < window || *suppressed > 0
+        });
+        match self.sent.get_mut(alert) {
+            Some((sent_at, suppressed)) if now.duration_since(*sent_at) < window => {
+                *suppressed += count;
+                None
+            }
+            Some((sent_at, suppressed)) => {
+                *sent_at = now;
+                Some((count, std::mem::take(suppressed)))
+            }
+            None => {
+                self.sent.insert(alert.to_string(), (now, 0));
+                Some((count, 0))
+            }
+        }
+    }
+}
+
+/// Sends error alerts to an admin chat, in the default language.
+///
+/// Alerts arriving within the batch delay of the first one are sent together, and identical
+/// ones are sent once per dedup window. Admins may mute alerts for a while.
+#[derive(Debug)]
+pub struct Alerts {
+    chat: Option<i64>,
+    batch_delay: Duration,
+    window: Duration,
+    rx: Mutex<Option<UnboundedReceiver<String>>>,
+    muted_until: Mutex<Option<Instant>>,
+    // dropped while muted
+    muted: Mutex<u64>,
+}
+
+/// Create the layer collecting alerts, and the sink sending them to `chat` once started.
+pub fn alerts(
+    chat: Option<i64>,
+    batch_delay: Duration,
+    window: Duration,
+) -> (AlertLayer, Arc<Alerts>) {
+    let (tx, rx) = unbounded_channel();
+    let alerts = Alerts {
+        chat,
+        batch_delay,
+        window,
+        rx: Mutex::new(Some(rx)),
+        muted_until: Mutex::new(None),
+        muted: Mutex::new(0),
+    };
+    (AlertLayer { tx }, Arc::new(alerts))
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    match s.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &s[..idx]),
+        None => s.to_string(),
+    }
+}
+
+impl Alerts {
+    pub fn enabled(&self) -> bool {
+        self.chat.is_some()
+    }
+    /// Mute alerts for `duration`, or unmute them if it's zero.
+    pub fn mute(&self, duration: Duration) {
+        *self.muted_until.lock() = (!duration.is_zero()).then(|| Instant::now() + duration);
+    }
+    /// When alerts are muted until, if they are.
+    pub fn muted_until(&self) -> Option<Instant> {
+        let mut muted_until = self.muted_until.lock();
+        if muted_until.is_some_and(|until| until <= Instant::now()) {
+            *muted_until = None;
+        }
+        *muted_until
+    }
+    // Merge a batch into a message, leaving out duplicates.
+    fn compose(&self, batch: Vec<String>, dedup: &mut Dedup) -> Option<String> {
+        let mut counts: Vec<(String, u64)> = vec![];
+        for alert in batch {
+            match counts.iter_mut().find(|(a, _)| *a == alert) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((alert, 1)),
+            }
+        }
+        let lang = Lang::default();
+        let now = Instant::now();
+        let mut lines = vec![];
+        let muted = std::mem::take(&mut *self.muted.lock());
+        if muted > 0 {
+            lines.push(lang.format("alert.muted", &[("count", &muted)]));
+        }
+        for (alert, count) in counts {
+            if let Some((count, suppressed)) = dedup.admit(&alert, count, now, self.window) {
+                let alert = truncate(&alert, MAX_ALERT_CHARS);
+                lines.push(if suppressed > 0 {
+                    lang.format(
+                        "alert.repeated_since",
+                        &[("alert", &alert), ("count", &(count + suppressed))],
+                    )
+                } else if count > 1 {
+                    lang.format("alert.repeated", &[("alert", &alert), ("count", &count)])
+                } else {
+                    alert
+                });
+            }
+        }
+        (!lines.is_empty()).then(|| truncate(&lines.join("\n\n"), MAX_MESSAGE_CHARS - 1))
+    }
+    /// Send alerts until shutdown. Alerts are dropped if there's no chat to send them to.
+    pub fn start(self: &Arc<Self>, bot: AutoSend<Bot>, shutdown: &Shutdown) {
+        let rx = self.rx.lock().take();
+        let (chat, mut rx) = match (self.chat, rx) {
+            (Some(chat), Some(rx)) => (chat, rx),
+            _ => return,
+        };
+        let alerts = self.clone();
+        let token = shutdown.token();
+        shutdown.track(tokio::spawn(async move {
+            let mut dedup = Dedup::default();
+            loop {
+                let first = tokio::select! {
+                    _ = token.cancelled() => break,
+                    alert = rx.recv() => match alert {
+                        Some(alert) => alert,
+                        None => break,
+                    },
+                };
+                let mut batch = vec![first];
+                let deadline = tokio::time::sleep(alerts.batch_delay);
+                tokio::pin!(deadline);
+                loop {
+                    tokio::select! {
+                        _ = &mut deadline => break,
+                        Some(alert) = rx.recv() => batch.push(alert),
+                    }
+                }
+
+                if alerts.muted_until().is_some() {
+                    *alerts.muted.lock() += batch.len() as u64;
+                    continue;
+                }
+                if let Some(text) = alerts.compose(batch, &mut dedup) {
+                    // not an error, or it would be sent again
+                    if let Err(e) = bot.send_message(chat, text).await {
+                        warn!("unable to send alerts: {:?}", e);
+                    }
+                }
+            }
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{alerts, Dedup};
+    use crate::shutdown::Shutdown;
+    use crate::testing::FakeApi;
+
+    const ADMIN_CHAT: i64 = -100;
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn suppressed_alerts_are_counted_when_sent_again() {
+        let mut dedup = Dedup::default();
+        let start = Instant::now();
+        assert_eq!(dedup.admit("boom", 2, start, WINDOW), Some((2, 0)));
+        assert_eq!(dedup.admit("boom", 3, start + WINDOW / 2, WINDOW), None);
+        assert_eq!(
+            dedup.admit("bang", 1, start + WINDOW / 2, WINDOW),
+            Some((1, 0))
+        );
+
+        assert_eq!(dedup.admit("boom", 1, start + WINDOW, WINDOW), Some((1, 3)));
+        // counted from the last one sent
+        assert_eq!(
+            dedup.admit("boom", 1, start + WINDOW * 2, WINDOW),
+            Some((1, 0))
+        );
+        // alerts without suppressed occurrences are forgotten
+        assert!(!dedup.sent.contains_key("bang"));
+    }
+
+    #[tokio::test]
+    async fn errors_are_alerted_once() {
+        let api = FakeApi::start(&[]);
+        let (layer, alerts) = alerts(
+            Some(ADMIN_CHAT),
+            Duration::from_millis(100),
+            Duration::from_secs(60),
+        );
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+        let shutdown = Shutdown::default();
+        alerts.start(api.bot(), &shutdown);
+
+        for _ in 0..3 {
+            tracing::error!("boom");
+        }
+        tracing::warn!("not alerted");
+        let alert = api
+            .wait_for("sendMessage", |p| p["chat_id"] == ADMIN_CHAT)
+            .await;
+        assert_eq!(alert["text"], "chi_tg_inline_rs::alert::tests: boom（×3）");
+
+        // duplicates within the window are left out
+        tracing::error!("boom");
+        tracing::error!("bang");
+        api.wait_for("sendMessage", |p| {
+            p["text"] == "chi_tg_inline_rs::alert::tests: bang"
+        })
+        .await;
+
+        alerts.mute(Duration::from_secs(60));
+        assert!(alerts.muted_until().is_some());
+        alerts.mute(Duration::ZERO);
+        assert!(alerts.muted_until().is_none());
+
+        shutdown.cancel();
+        shutdown.join(Duration::from_secs(1)).await;
+    }
+}
@@ -1,22 +1,102 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
+use crate::corpus::MediaKind;
+
+// How long after being shown a result may still be chosen, on top of the cache time.
+const CHOICE_WINDOW: Duration = Duration::from_secs(10 * 60);
+// Expired stat cards and answers are dropped once this many of either are booked.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Results shown to users, so that chosen ones can be told apart and logged.
+///
+/// Stat cards and answers expire, as answers are keyed by text with user arguments in it.
 #[derive(Debug, Default)]
 pub struct Booking {
-    stats: HashSet<String>,
-    answers: HashMap<String, String>,
+    // when each stat card was last shown
+    stats: HashMap<String, Instant>,
+    // templates of answers, and when they were last shown
+    answers: HashMap<String, (String, Instant)>,
+    // how long stat cards and answers stay booked
+    ttl: Duration,
+    media: HashMap<String, (MediaKind, String)>,
 }
 
 impl Booking {
-    pub fn check_stat(&mut self, hash: &str) -> bool {
-        self.stats.remove(hash)
+    /// Bookings of results cached by Telegram for `personal_cache_time`.
+    pub fn new(personal_cache_time: Duration) -> Self {
+        Self {
+            ttl: personal_cache_time + CHOICE_WINDOW,
+            ..Self::default()
+        }
+    }
+    // Stat cards stay booked while Telegram may serve them again from its cache.
+    pub fn check_stat(&self, hash: &str) -> bool {
+        self.stats
+            .get(hash)
+            .is_some_and(|shown| shown.elapsed() < self.ttl)
     }
     pub fn get_answer(&self, hash: &str) -> Option<String> {
-        self.answers.get(hash).cloned()
+        self.answers
+            .get(hash)
+            .filter(|(_, shown)| shown.elapsed() < self.ttl)
+            .map(|(template, _)| template.clone())
+    }
+    pub fn get_media(&self, hash: &str) -> Option<(MediaKind, String)> {
+        self.media.get(hash).cloned()
     }
     pub fn book_stat(&mut self, hash: String) {
-        self.stats.insert(hash);
+        let now = Instant::now();
+        if self.stats.len() > PRUNE_THRESHOLD {
+            let ttl = self.ttl;
+            self.stats.retain(|_, shown| now - *shown < ttl);
+        }
+        self.stats.insert(hash, now);
     }
     pub fn book_answer(&mut self, hash: String, answer: String) {
-        self.answers.insert(hash, answer);
+        let now = Instant::now();
+        if self.answers.len() > PRUNE_THRESHOLD {
+            let ttl = self.ttl;
+            self.answers.retain(|_, (_, shown)| now - *shown < ttl);
+        }
+        self.answers.insert(hash, (answer, now));
+    }
+    pub fn book_media(&mut self, hash: String, kind: MediaKind, file_id: String) {
+        self.media.insert(hash, (kind, file_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_cards_expire() {
+        let mut booking = Booking::new(Duration::ZERO);
+        booking.book_stat(String::from("card"));
+        assert!(booking.check_stat("card"));
+        assert!(!booking.check_stat("other"));
+
+        // cards booked for no time at all expire right away, and are dropped eventually
+        let mut booking = Booking::default();
+        for i in 0..=PRUNE_THRESHOLD + 1 {
+            booking.book_stat(i.to_string());
+        }
+        assert!(!booking.check_stat("0"));
+        assert_eq!(booking.stats.len(), 1);
+    }
+
+    #[test]
+    fn answers_expire() {
+        let mut booking = Booking::new(Duration::ZERO);
+        booking.book_answer(String::from("hash"), String::from("{name}好菜"));
+        assert_eq!(booking.get_answer("hash").as_deref(), Some("{name}好菜"));
+
+        let mut booking = Booking::default();
+        for i in 0..=PRUNE_THRESHOLD + 1 {
+            booking.book_answer(i.to_string(), String::from("我好菜啊"));
+        }
+        assert_eq!(booking.get_answer("0"), None);
+        assert_eq!(booking.answers.len(), 1);
     }
 }
@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::seller::MoanGrammar;
+
+/// One of the bots served by the process, each with its own corpus and database.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BotConfig {
+    /// Tells the bots apart in logs and webhook paths, so it's unique and made of
+    /// `[A-Za-z0-9_-]`.
+    pub name: String,
+    pub token: String,
+    pub corpus_url: Url,
+    /// Database of the bot's stats, favorites, suggestions and the like.
+    pub db: String,
+    #[serde(default)]
+    pub moan: MoanGrammar,
+}
+
+impl BotConfig {
+    /// Read the bots listed in the JSON file at `APP_BOTS`, or a single bot from
+    /// `TELOXIDE_TOKEN`, `APP_CORPUS_URL` and `APP_MONGODB_DBNAME`.
+    pub fn from_env() -> Vec<Self> {
+        if let Ok(path) = env::var("APP_BOTS") {
+            let f = File::open(path).expect("unable to open bot list");
+            return Self::from_reader(f);
+        }
+        vec![Self {
+            name: String::from("default"),
+            token: env::var("TELOXIDE_TOKEN").expect("missing bot token"),
+            corpus_url: env::var("APP_CORPUS_URL")
+                .expect("missing corpus url")
+                .parse()
+                .expect("malformed url"),
+            db: env::var("APP_MONGODB_DBNAME").expect("missing mongodb dbname"),
+            moan: MoanGrammar::default(),
+        }]
+    }
+    /// Read a JSON list of bots.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the list is malformed or empty, or a name is invalid or taken.
+    pub fn from_reader(reader: impl Read) -> Vec<Self> {
+        let bots: Vec<Self> = serde_json::from_reader(reader).expect("malformed bot list");
+        assert!(!bots.is_empty(), "empty bot list");
+        let mut names = HashSet::new();
+        for bot in &bots {
+            assert!(
+                !bot.name.is_empty()
+                    && bot
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                "invalid bot name {:?}, only letters, digits, `_` and `-` are allowed",
+                bot.name
+            );
+            assert!(names.insert(&bot.name), "duplicate bot name {:?}", bot.name);
+        }
+        bots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bots(names: &[&str]) -> String {
+        let bots: Vec<_> = names
+            .iter()
+            .map(|name| {
+                serde_json::json!({
+                    "name": name,
+                    "token": "123:abc",
+                    "corpus_url": "https://example.com/",
+                    "db": name
+                })
+            })
+            .collect();
+        serde_json::to_string(&bots).unwrap()
+    }
+
+    #[test]
+    fn bots_are_read() {
+        let bots = BotConfig::from_reader(bots(&["chi", "other-bot_2"]).as_bytes());
+        assert_eq!(bots.len(), 2);
+        assert_eq!(bots[1].name, "other-bot_2");
+        assert_eq!(bots[1].db, "other-bot_2");
+        // the grammar is optional
+        assert_eq!(bots[1].moan.moans, MoanGrammar::default().moans);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty bot list")]
+    fn empty_list_is_rejected() {
+        BotConfig::from_reader("[]".as_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate bot name")]
+    fn duplicate_names_are_rejected() {
+        BotConfig::from_reader(bots(&["chi", "chi"]).as_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid bot name")]
+    fn names_outside_path_segments_are_rejected() {
+        BotConfig::from_reader(bots(&["chi/../admin"]).as_bytes());
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// How long Telegram may cache inline query results, in seconds.
+///
+/// With a non-zero `shared` time, results without anything personal in them are cached
+/// for everyone, and picked deterministically within each window of that length.
+/// Results specific to the user are cached for `personal` seconds.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Caching {
+    pub shared: u32,
+    pub personal: u32,
+}
+
+impl Caching {
+    pub const fn is_shared(self) -> bool {
+        self.shared > 0
+    }
+    /// RNG to pick results for `query` with.
+    ///
+    /// When shared, the same query gets the same results until the window ends, so
+    /// fresh answers agree with what Telegram has cached.
+    pub fn rng(self, query: &str) -> StdRng {
+        if !self.is_shared() {
+            return StdRng::from_entropy();
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.rng_at(query, now)
+    }
+    // RNG for `query` in the shared window containing `now`, in seconds since the epoch.
+    fn rng_at(self, query: &str, now: u64) -> StdRng {
+        let window = now / u64::from(self.shared);
+        let digest = md5::compute(format!("{}:{}", window, query));
+        let mut seed = [0; 8];
+        seed.copy_from_slice(&digest[..8]);
+        StdRng::seed_from_u64(u64::from_le_bytes(seed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    const CACHING: Caching = Caching {
+        shared: 60,
+        personal: 0,
+    };
+
+    fn draw(query: &str, now: u64) -> u64 {
+        CACHING.rng_at(query, now).gen()
+    }
+
+    #[test]
+    fn same_window_agrees() {
+        assert_eq!(draw("query", 120), draw("query", 179));
+    }
+
+    #[test]
+    fn windows_and_queries_diverge() {
+        assert_ne!(draw("query", 179), draw("query", 180));
+        assert_ne!(draw("query", 120), draw("other", 120));
+    }
+}
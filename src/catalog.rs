@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::options::ReplaceOptions;
+use mongodb::{Collection, Database};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use serde::{Deserialize, Serialize};
+
+use crate::corpus::Media;
+use crate::errors::Result;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub file_unique_id: String,
+    #[serde(flatten)]
+    pub media: Media,
+}
+
+/// Locally managed media ingested by admins, merged with the remote corpus.
+#[derive(Debug)]
+pub struct MediaCatalog {
+    coll: Collection<CatalogEntry>,
+    entries: RwLock<Vec<CatalogEntry>>,
+    // media waiting for tags, keyed by admin user id
+    pending: Mutex<HashMap<i64, CatalogEntry>>,
+}
+
+impl MediaCatalog {
+    pub async fn new(db: &Database) -> Result<Self> {
+        let coll = db.collection("catalog");
+        let entries = coll.find(None, None).await?.try_collect().await?;
+        Ok(Self {
+            coll,
+            entries: RwLock::new(entries),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+    /// No media, without reading `db`.
+    #[cfg(test)]
+    pub fn empty(db: &Database) -> Self {
+        Self {
+            coll: db.collection("catalog"),
+            entries: RwLock::default(),
+            pending: Mutex::default(),
+        }
+    }
+    pub fn entries(&self) -> RwLockReadGuard<Vec<CatalogEntry>> {
+        self.entries.read()
+    }
+    pub fn set_pending(&self, user: i64, entry: CatalogEntry) {
+        self.pending.lock().insert(user, entry);
+    }
+    pub fn take_pending(&self, user: i64) -> Option<CatalogEntry> {
+        self.pending.lock().remove(&user)
+    }
+    pub async fn add(&self, entry: CatalogEntry) -> Result<()> {
+        self.coll
+            .replace_one(
+                doc! {"file_unique_id": &entry.file_unique_id},
+                &entry,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        let mut entries = self.entries.write();
+        entries.retain(|item| item.file_unique_id != entry.file_unique_id);
+        entries.push(entry);
+
+        Ok(())
+    }
+    /// Remove an entry by its unique file id. Returns whether anything was removed.
+    pub async fn remove(&self, file_unique_id: &str) -> Result<bool> {
+        let result = self
+            .coll
+            .delete_one(doc! {"file_unique_id": file_unique_id}, None)
+            .await?;
+
+        self.entries
+            .write()
+            .retain(|item| item.file_unique_id != file_unique_id);
+
+        Ok(result.deleted_count > 0)
+    }
+}
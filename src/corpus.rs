@@ -1,15 +1,114 @@
+use itertools::Itertools;
 use parking_lot::{RwLock, RwLockReadGuard};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Sticker,
+    Gif,
+    Voice,
+}
+
+impl MediaKind {
+    pub const ALL: [Self; 3] = [Self::Sticker, Self::Gif, Self::Voice];
+
+    /// The catalog key of the kind's name.
+    pub const fn message_key(self) -> &'static str {
+        match self {
+            Self::Sticker => "media.sticker",
+            Self::Gif => "media.gif",
+            Self::Voice => "media.voice",
+        }
+    }
+    const fn file_name(self) -> &'static str {
+        match self {
+            Self::Sticker => "sticker.txt",
+            Self::Gif => "gif.txt",
+            Self::Voice => "voice.txt",
+        }
+    }
+}
+
+/// The file the common sentences of a corpus are read from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CommonFormat {
+    /// `common.txt`, with a sentence per line.
+    Plain,
+    /// `common.jsonl`, with an [`Entry`] per line.
+    Tagged,
+}
+
+impl CommonFormat {
+    pub const fn file_name(self) -> &'static str {
+        match self {
+            Self::Plain => "common.txt",
+            Self::Tagged => "common.jsonl",
+        }
+    }
+}
+
+/// A Telegram file with tags for searching.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Media {
+    pub kind: MediaKind,
+    pub file_id: String,
+    pub tags: Vec<String>,
+}
+
+const fn default_weight() -> f64 {
+    1.0
+}
+
+/// A corpus sentence with optional metadata.
+///
+/// Sentences from `common.txt` only have their text, while `common.jsonl` may
+/// provide the other fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub text: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub author: Option<String>,
+    pub source: Option<String>,
+    /// Date the sentence was added, e.g. `2021-10-28`.
+    pub added: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    #[serde(default)]
+    pub nsfw: bool,
+}
+
+impl Entry {
+    pub const fn plain(text: String) -> Self {
+        Self {
+            text,
+            tags: vec![],
+            author: None,
+            source: None,
+            added: None,
+            weight: default_weight(),
+            nsfw: false,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Corpus {
-    pub common: Vec<String>,
+    /// Short hash of the sentences, telling corpus updates apart.
+    pub version: String,
+    pub format: CommonFormat,
+    pub common: Vec<Entry>,
+    /// Lines of the common file, blank ones included.
+    pub lines: usize,
     pub refuse: Vec<String>,
     pub trigger: Vec<String>,
     pub phrase: Vec<Vec<String>>,
+    pub media: Vec<Media>,
 }
 
 #[derive(Debug)]
@@ -19,12 +118,12 @@ pub struct CorpusClient {
     corpus: RwLock<Corpus>,
 }
 
+async fn fetch_text(client: &Client, url: Url) -> Result<String> {
+    Ok(client.get(url.as_str()).send().await?.text().await?)
+}
+
 async fn fetch(client: &Client, url: Url) -> Result<Vec<String>> {
-    Ok(client
-        .get(url.as_str())
-        .send()
-        .await?
-        .text()
+    Ok(fetch_text(client, url)
         .await?
         .trim()
         .split('\n')
@@ -32,50 +131,123 @@ async fn fetch(client: &Client, url: Url) -> Result<Vec<String>> {
         .collect())
 }
 
-impl CorpusClient {
-    pub async fn new_with_url(base_url: &Url) -> Result<Self> {
-        let client = Client::new();
+// Fetch a file that may be missing from the corpus.
+async fn fetch_optional(client: &Client, url: Url) -> Result<Option<String>> {
+    let resp = client.get(url.as_str()).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?.text().await?))
+}
 
-        let common = fetch(&client, base_url.join("common.txt").unwrap()).await?;
-        let refuse = fetch(&client, base_url.join("refuse.txt").unwrap()).await?;
-        let trigger = fetch(&client, base_url.join("trigger.txt").unwrap()).await?;
-        let phrase = fetch(&client, base_url.join("phrase.txt").unwrap())
-            .await?
-            .into_iter()
-            .map(|s| s.split(' ').map(ToString::to_string).collect())
-            .collect();
+// Prefer the tagged `common.jsonl`, and fall back to the plain `common.txt`.
+// Also returns the number of lines of the file.
+async fn fetch_common(
+    client: &Client,
+    base_url: &Url,
+) -> Result<(CommonFormat, Vec<Entry>, usize)> {
+    let tagged = CommonFormat::Tagged.file_name();
+    if let Some(text) = fetch_optional(client, base_url.join(tagged)?).await? {
+        let common = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(Into::into))
+            .collect::<Result<_>>()?;
+        return Ok((CommonFormat::Tagged, common, text.lines().count()));
+    }
+    let text = fetch_text(client, base_url.join(CommonFormat::Plain.file_name())?).await?;
+    let common = text
+        .trim()
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Entry::plain(line.to_string()))
+        .collect();
+    Ok((CommonFormat::Plain, common, text.lines().count()))
+}
 
-        let corpus = Corpus {
-            common,
-            refuse,
-            trigger,
-            phrase,
+// Media sections are optional, so a missing file yields an empty section.
+// Each line is a file id followed by space-separated tags.
+async fn fetch_media(client: &Client, base_url: &Url) -> Result<Vec<Media>> {
+    let mut media = vec![];
+    for kind in MediaKind::ALL {
+        let text = match fetch_optional(client, base_url.join(kind.file_name())?).await? {
+            Some(text) => text,
+            None => continue,
         };
+        media.extend(text.lines().filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            parts.next().map(|file_id| Media {
+                kind,
+                file_id: file_id.to_string(),
+                tags: parts.map(ToString::to_string).collect(),
+            })
+        }));
+    }
+    Ok(media)
+}
 
+// Fetch the whole corpus, which must have some sentences.
+async fn fetch_corpus(client: &Client, base_url: &Url) -> Result<Corpus> {
+    let (format, common, lines) = fetch_common(client, base_url).await?;
+    if common.is_empty() {
+        return Err(Error::CorpusEmpty);
+    }
+    let refuse = fetch(client, base_url.join("refuse.txt")?).await?;
+    let trigger = fetch(client, base_url.join("trigger.txt")?).await?;
+    let phrase = fetch(client, base_url.join("phrase.txt")?)
+        .await?
+        .into_iter()
+        .map(|s| s.split(' ').map(ToString::to_string).collect())
+        .collect();
+    let media = fetch_media(client, base_url).await?;
+    let version = format!(
+        "{:x}",
+        md5::compute(common.iter().map(|entry| entry.text.as_str()).join("\n"))
+    );
+    Ok(Corpus {
+        version: version[..8].to_string(),
+        format,
+        common,
+        lines,
+        refuse,
+        trigger,
+        phrase,
+        media,
+    })
+}
+
+impl CorpusClient {
+    pub async fn new_with_url(base_url: &Url) -> Result<Self> {
+        let client = Client::new();
+        let corpus = fetch_corpus(&client, base_url).await?;
         Ok(Self {
             client,
             base_url: base_url.clone(),
             corpus: RwLock::new(corpus),
         })
     }
+    /// Refetch the corpus, keeping the current one if that fails.
     pub async fn update(&self) -> Result<()> {
-        let base_url = &self.base_url;
-        let client = &self.client;
-
-        let common = fetch(client, base_url.join("common.txt").unwrap()).await?;
-        let refuse = fetch(client, base_url.join("refuse.txt").unwrap()).await?;
-        let trigger = fetch(client, base_url.join("trigger.txt").unwrap()).await?;
-        let phrase = fetch(client, base_url.join("phrase.txt").unwrap())
-            .await?
-            .into_iter()
-            .map(|s| s.split(' ').map(ToString::to_string).collect())
-            .collect();
+        let Corpus {
+            version,
+            format,
+            common,
+            lines,
+            refuse,
+            trigger,
+            phrase,
+            media,
+        } = fetch_corpus(&self.client, &self.base_url).await?;
 
         let mut corpus = self.corpus.write();
+        corpus.version = version;
+        corpus.format = format;
         corpus.common = common;
+        corpus.lines = lines;
         corpus.refuse = refuse;
         corpus.trigger = trigger;
         corpus.phrase = phrase;
+        corpus.media = media;
 
         Ok(())
     }
@@ -83,3 +255,49 @@ impl CorpusClient {
         self.corpus.read()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeApi, CORPUS};
+
+    #[tokio::test]
+    async fn corpus_is_fetched_from_fake_api() {
+        let api = FakeApi::start(&CORPUS);
+        let corpus = CorpusClient::new_with_url(&api.corpus_url()).await.unwrap();
+        let corpus = corpus.corpus();
+        assert_eq!(corpus.common.len(), 3);
+        assert_eq!(corpus.common[0].text, "我好菜啊");
+        // media sections are optional
+        assert!(corpus.media.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tagged_corpus_is_preferred() {
+        let mut corpus = CORPUS.to_vec();
+        corpus.push((
+            "common.jsonl",
+            r#"{"text": "我好菜啊", "tags": ["自嘲"], "weight": 2.0}
+{"text": "涩涩", "nsfw": true}"#,
+        ));
+        let api = FakeApi::start(&corpus);
+        let corpus = CorpusClient::new_with_url(&api.corpus_url()).await.unwrap();
+        let corpus = corpus.corpus();
+        assert_eq!(corpus.common.len(), 2);
+        assert_eq!(corpus.common[0].tags, vec!["自嘲"]);
+        assert!((corpus.common[0].weight - 2.0).abs() < f64::EPSILON);
+        assert!(corpus.common[1].nsfw);
+    }
+
+    #[tokio::test]
+    async fn empty_corpus_is_refused() {
+        let api = FakeApi::start(&[
+            ("common.txt", "\n"),
+            ("refuse.txt", ""),
+            ("trigger.txt", ""),
+            ("phrase.txt", ""),
+        ]);
+        let result = CorpusClient::new_with_url(&api.corpus_url()).await;
+        assert!(matches!(result, Err(Error::CorpusEmpty)));
+    }
+}
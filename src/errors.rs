@@ -1,4 +1,6 @@
+use teloxide::types::Update;
 use thiserror::Error;
+use tracing::Span;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -10,8 +12,28 @@ pub enum Error {
     Json(#[from] serde_json::Error),
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
+    #[error("url error: {0}")]
+    Url(#[from] url::ParseError),
     #[error("mongodb error: {0}")]
     DB(#[from] mongodb::error::Error),
+    #[error("bson error: {0}")]
+    Bson(#[from] mongodb::bson::ser::Error),
     #[error("telegram request error: {0}")]
     Telegram(#[from] teloxide::RequestError),
+    #[error("corpus has no sentences")]
+    CorpusEmpty,
+    /// The store didn't return a document it should have, e.g. after an upsert.
+    #[error("store unavailable: no {0} returned")]
+    StoreUnavailable(&'static str),
+    #[error("not an admin")]
+    NotAdmin,
+    /// An error from a handler, along with the update being handled.
+    #[error("{source}")]
+    Handling {
+        update: Box<Update>,
+        #[source]
+        source: Box<Error>,
+        /// The span of the update, to report the error in.
+        span: Span,
+    },
 }
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{Error, Result};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Favorite {
+    pub user: String,
+    pub sentences: Vec<String>,
+}
+
+/// Starred sentences of each (masked) user.
+#[derive(Debug)]
+pub struct Favorites {
+    coll: Collection<Favorite>,
+    favorites: RwLock<HashMap<String, Vec<String>>>,
+}
+
+impl Favorites {
+    pub async fn new(db: &Database) -> Result<Self> {
+        let coll = db.collection("favorites");
+        let favorites = coll
+            .find(doc! {"user": {"$exists": true}}, None)
+            .await?
+            .map_ok(|favorite: Favorite| (favorite.user, favorite.sentences))
+            .try_collect()
+            .await?;
+        Ok(Self {
+            coll,
+            favorites: RwLock::new(favorites),
+        })
+    }
+    /// No favorites, without reading `db`.
+    #[cfg(test)]
+    pub fn empty(db: &Database) -> Self {
+        Self {
+            coll: db.collection("favorites"),
+            favorites: RwLock::default(),
+        }
+    }
+    pub fn get(&self, user: &str) -> Vec<String> {
+        self.favorites.read().get(user).cloned().unwrap_or_default()
+    }
+    async fn update(&self, user: String, update: Document) -> Result<()> {
+        let favorite = self
+            .coll
+            .find_one_and_update(
+                doc! {"user": user},
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or(Error::StoreUnavailable("favorite"))?;
+
+        self.favorites
+            .write()
+            .insert(favorite.user, favorite.sentences);
+
+        Ok(())
+    }
+    pub async fn add(&self, user: String, sentence: String) -> Result<()> {
+        self.update(user, doc! {"$addToSet": {"sentences": sentence}})
+            .await
+    }
+    pub async fn remove(&self, user: String, sentence: String) -> Result<()> {
+        self.update(user, doc! {"$pull": {"sentences": sentence}})
+            .await
+    }
+    pub async fn forget(&self, user: &str) -> Result<()> {
+        self.coll.delete_one(doc! {"user": user}, None).await?;
+        self.favorites.write().remove(user);
+        Ok(())
+    }
+}
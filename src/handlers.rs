@@ -1,136 +1,1074 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
+use mongodb::bson::oid::ObjectId;
 use parking_lot::RwLock;
 use teloxide::adaptors::AutoSend;
-use teloxide::payloads::AnswerInlineQuerySetters;
+use teloxide::payloads::{
+    AnswerCallbackQuerySetters, AnswerInlineQuerySetters, SendDocumentSetters, SendMessageSetters,
+};
 use teloxide::requests::Requester;
 use teloxide::types::{
-    ChosenInlineResult, InlineQuery, InlineQueryResult, InlineQueryResultArticle,
-    InputMessageContent, InputMessageContentText, Message,
+    CallbackQuery, ChatType, ChosenInlineResult, InlineKeyboardButton, InlineKeyboardMarkup,
+    InlineQuery, InputFile, Me, Message,
 };
 use teloxide::Bot;
+use tracing::debug;
 
+use crate::alert::Alerts;
+use crate::cache::Caching;
+use crate::catalog::CatalogEntry;
+use crate::corpus::{CorpusClient, Media, MediaKind};
 use crate::errors::Error;
-use crate::{mask_user, Booking, Command, MongoDBLogger, Seller};
+use crate::favorites::Favorites;
+use crate::i18n::Lang;
+use crate::jobs::{JobStatus, Scheduler};
+use crate::leaderboard::{Leaderboard, MAX_NAME_CHARS};
+use crate::menu::Menu;
+use crate::query::{Mode, ParsedQuery};
+use crate::ratelimit::Limits;
+use crate::seller::Order;
+use crate::stats::{Stat, Summary, MOAN_SENTENCE};
+use crate::suggestion::{export_patch, Suggestion, SuggestionQueue, MAX_SUGGESTION_CHARS};
+use crate::{
+    AdminCommand, Admins, Booking, Command, History, MediaCatalog, MongoDBLogger, Renderer, Seller,
+};
+
+const REVIEW_BATCH: i64 = 10;
+const RECENT_SHOWN: usize = 3;
+// Telegram accepts at most 50 results per inline query.
+const MAX_RESULTS: usize = 50;
+const MOAN_COUNT: usize = 5;
+const RANK_SIZE: usize = 10;
+// Not booked, so that chosen rate limit notices are never logged.
+const LIMITED_RESULT_ID: &str = "limited";
+// Rate limited users get the same notice for a while without reaching the bot.
+const LIMITED_CACHE_SECS: u32 = 10;
+const DEFAULT_MUTE_MINUTES: u64 = 60;
+// A week, keeping mute deadlines far from overflowing.
+const MAX_MUTE_MINUTES: u64 = 7 * 24 * 60;
+
+// Book answers so that chosen results are logged by their template.
+// `salt` keeps result ids of the same sentence distinct across sections.
+fn book_answers(
+    booking: &RwLock<Booking>,
+    answers: Vec<(String, String)>,
+    salt: &str,
+) -> Vec<(String, String, String)> {
+    let mut booking = booking.write();
+    answers
+        .into_iter()
+        .map(|(template, s)| {
+            let hash = format!("{:x}", md5::compute(format!("{}{}", salt, s)));
+            booking.book_answer(hash.clone(), template.clone());
+            (hash, template, s)
+        })
+        .collect()
+}
+
+fn book_media(booking: &RwLock<Booking>, media: Vec<Media>) -> Vec<(String, Media)> {
+    let mut booking = booking.write();
+    media
+        .into_iter()
+        .map(|media| {
+            let hash = format!("{:x}", md5::compute(&media.file_id));
+            booking.book_media(hash.clone(), media.kind, media.file_id.clone());
+            (hash, media)
+        })
+        .collect()
+}
+
+// NSFW sentences are only sold in private chats.
+fn allows_nsfw(query: &InlineQuery) -> bool {
+    matches!(query.chat_type, Some(ChatType::Sender | ChatType::Private))
+}
+
+// Shared results are cached for everyone, so they're in the default language.
+fn result_lang(query: &InlineQuery, shared: bool) -> Lang {
+    if shared {
+        Lang::default()
+    } else {
+        Lang::of(&query.from)
+    }
+}
+
+fn message_lang(msg: &Message) -> Lang {
+    msg.from().map_or_else(Lang::default, Lang::of)
+}
+
+fn format_summary(summary: Summary, tags: Vec<(String, u64)>, lang: Lang) -> String {
+    let top_sentences_formatted = summary
+        .top_sentences
+        .into_iter()
+        .map(|(s, count)| {
+            let item = if s == MOAN_SENTENCE {
+                lang.text("moan.title")
+            } else {
+                &s
+            };
+            lang.format("stat.count", &[("item", &item), ("count", &count)])
+        })
+        .join("\n");
+    let media_formatted = summary
+        .media
+        .into_iter()
+        .map(|(kind, count)| {
+            lang.format(
+                "stat.count",
+                &[("item", &lang.text(kind.message_key())), ("count", &count)],
+            )
+        })
+        .join("\n");
+    let tags_formatted = tags
+        .into_iter()
+        .map(|(tag, count)| lang.format("stat.tag_count", &[("tag", &tag), ("count", &count)]))
+        .join("\n");
+    lang.format(
+        "stat.summary",
+        &[
+            ("users", &summary.users),
+            ("total", &summary.total),
+            ("top", &summary.top_user_count),
+            ("sentences", &top_sentences_formatted),
+            ("media", &media_formatted),
+            ("tags", &tags_formatted),
+        ],
+    )
+}
+
+// Users who opted in are shown by their display names, the others stay anonymous.
+fn format_rank(stats: &Stat, names: &HashMap<String, String>, lang: Lang) -> String {
+    let rank = stats
+        .users
+        .iter()
+        .sorted_by_key(|item| -(*item.1 as i128))
+        .take(RANK_SIZE)
+        .enumerate()
+        .map(|(idx, (user, count))| match names.get(user) {
+            Some(name) => lang.format(
+                "rank.named",
+                &[("place", &(idx + 1)), ("name", name), ("count", count)],
+            ),
+            None => lang.format(
+                "rank.anonymous",
+                &[
+                    ("place", &(idx + 1)),
+                    ("user", &&user[..4]),
+                    ("count", count),
+                ],
+            ),
+        })
+        .join("\n");
+    format!("{}\n\n{}", lang.text("rank.title"), rank)
+}
 
+// The personal card of how many sentences the user has sold.
+fn format_user_stat(stats: &Stat, user: &str, lang: Lang) -> String {
+    let sell_count = stats.users.get(user).copied().unwrap_or(0);
+    let key = if sell_count > 20 {
+        "stat.user_many"
+    } else {
+        "stat.user"
+    };
+    lang.format(key, &[("count", &sell_count)])
+}
+
+/// Answer plain searches and random picks.
+///
+/// With shared caching, searches leave out everything personal (the user's stat card, recent
+/// sentences and NSFW ones) so that the results may be cached for everyone.
+#[allow(clippy::too_many_arguments)]
 pub async fn inline_query_handler(
     query: InlineQuery,
+    parsed: ParsedQuery,
     bot: AutoSend<Bot>,
     logger: Arc<MongoDBLogger>,
     seller: Arc<Seller>,
+    renderer: Arc<Renderer>,
+    history: Arc<History>,
     booking: Arc<RwLock<Booking>>,
+    caching: Caching,
 ) -> Result<(), Error> {
-    let sell_count = logger
-        .stats()
-        .users
-        .get(&mask_user(query.from.id))
-        .copied()
-        .unwrap_or(0);
-    let sell_stat = format!(
-        "我已经卖了 {} 句菜{}",
-        sell_count,
-        if sell_count > 20 { "，我 zc" } else { "" }
-    );
-    let sell_stat_hash = format!("{:x}", md5::compute(&sell_stat));
-
-    // booking stat resp so we won't count it into user sell log
-    booking.write().book_stat(sell_stat_hash.clone());
+    let shared = caching.is_shared() && parsed.mode != Mode::Random;
+    let lang = result_lang(&query, shared);
+    let user = logger.mask_user(query.from.id);
+    let sell_stat = (!shared).then(|| {
+        let sell_stat = format_user_stat(&logger.stats(), &user, lang);
+        let sell_stat_hash = format!("{:x}", md5::compute(&sell_stat));
+        // booking stat resp so we won't count it into user sell log
+        booking.write().book_stat(sell_stat_hash.clone());
+        (sell_stat_hash, sell_stat)
+    });
 
-    let moan = seller.moan();
+    let keyword = parsed.keyword.as_str();
+    let args = parsed.args();
+    let order = Order {
+        keyword,
+        tag: None,
+        args: &args,
+        nsfw: !shared && allows_nsfw(&query),
+    };
+    let mut rng = caching.rng(&query.query);
+    let moan = seller.moan(&mut rng);
     let moan_hash = format!("{:x}", md5::compute(&moan));
+    let (answers, recent_answers, media) = if parsed.mode == Mode::Random {
+        (seller.sell_random(&order, &mut rng), vec![], vec![])
+    } else if shared {
+        (
+            seller.sell(&order, &logger.stats(), &[], &mut rng),
+            vec![],
+            seller.sell_media(keyword, &mut rng),
+        )
+    } else {
+        let recent = history.recent(&user);
+        let answers = seller.sell(&order, &logger.stats(), &recent, &mut rng);
+        (
+            answers,
+            seller.resell(&order, &recent, RECENT_SHOWN),
+            seller.sell_media(keyword, &mut rng),
+        )
+    };
+    // log the template instead of the rendered text
+    let answers = book_answers(&booking, answers, "");
+    // distinct from the hash of random answers in case the same sentence is picked
+    let recent_answers = book_answers(&booking, recent_answers, "recent:");
+    let media = book_media(&booking, media);
 
-    let answers = {
-        let mut booking = booking.write();
-        seller
-            .sell(query.query.as_str())
+    let results = {
+        let stats = logger.stats();
+        vec![renderer.moan(moan_hash, moan, lang)]
             .into_iter()
-            .map(|s| {
-                let hash = format!("{:x}", md5::compute(&s));
-                booking.book_answer(hash.clone(), s.clone());
-                (hash, s)
+            .chain(answers.into_iter().map(|(hash, template, s)| {
+                let sold = stats.sentences.get(&template).copied().unwrap_or(0);
+                renderer.sentence(hash, &template, &s, sold, lang)
+            }))
+            .chain(
+                recent_answers
+                    .into_iter()
+                    .map(|(hash, template, s)| renderer.recent(hash, &template, &s, lang)),
+            )
+            .chain(
+                media
+                    .into_iter()
+                    .map(|(hash, media)| renderer.media(hash, &media, lang)),
+            )
+            .chain(sell_stat.map(|(hash, sell_stat)| renderer.stat(hash, sell_stat, lang)))
+            .collect_vec()
+    };
+
+    if shared {
+        bot.answer_inline_query(&query.id, results)
+            .cache_time(caching.shared)
+            .await?;
+    } else {
+        bot.answer_inline_query(&query.id, results)
+            .is_personal(true)
+            .cache_time(caching.personal)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Answer [`Mode::Favorite`] queries with the user's favorites.
+#[allow(clippy::too_many_arguments)]
+pub async fn favorite_query_handler(
+    query: InlineQuery,
+    parsed: ParsedQuery,
+    bot: AutoSend<Bot>,
+    logger: Arc<MongoDBLogger>,
+    seller: Arc<Seller>,
+    renderer: Arc<Renderer>,
+    favorites: Arc<Favorites>,
+    booking: Arc<RwLock<Booking>>,
+    caching: Caching,
+) -> Result<(), Error> {
+    let lang = Lang::of(&query.from);
+    let favorites = favorites.get(&logger.mask_user(query.from.id));
+    let args = parsed.args();
+    let order = Order {
+        keyword: &parsed.keyword,
+        tag: None,
+        args: &args,
+        nsfw: allows_nsfw(&query),
+    };
+    let answers = seller.resell(&order, &favorites, MAX_RESULTS);
+    let results = book_answers(&booking, answers, "favorite:")
+        .into_iter()
+        .map(|(hash, template, s)| renderer.favorite(hash, &template, &s, lang))
+        .collect_vec();
+
+    bot.answer_inline_query(&query.id, results)
+        .is_personal(true)
+        .cache_time(caching.personal)
+        .await?;
+    Ok(())
+}
+
+/// Answer [`Mode::Tag`] queries with sentences and media tagged with the given tag.
+#[allow(clippy::too_many_arguments)]
+pub async fn tag_query_handler(
+    query: InlineQuery,
+    parsed: ParsedQuery,
+    bot: AutoSend<Bot>,
+    logger: Arc<MongoDBLogger>,
+    seller: Arc<Seller>,
+    renderer: Arc<Renderer>,
+    history: Arc<History>,
+    booking: Arc<RwLock<Booking>>,
+    caching: Caching,
+) -> Result<(), Error> {
+    let shared = caching.is_shared();
+    let lang = result_lang(&query, shared);
+    let args = parsed.args();
+    let order = Order {
+        keyword: "",
+        tag: Some(&parsed.keyword),
+        args: &args,
+        nsfw: !shared && allows_nsfw(&query),
+    };
+    let recent = if shared {
+        vec![]
+    } else {
+        history.recent(&logger.mask_user(query.from.id))
+    };
+    let mut rng = caching.rng(&query.query);
+    let answers = seller.sell(&order, &logger.stats(), &recent, &mut rng);
+    let answers = book_answers(&booking, answers, "");
+    let media = book_media(
+        &booking,
+        seller.sell_tagged_media(&parsed.keyword, &mut rng),
+    );
+    let results = {
+        let stats = logger.stats();
+        answers
+            .into_iter()
+            .map(|(hash, template, s)| {
+                let sold = stats.sentences.get(&template).copied().unwrap_or(0);
+                renderer.sentence(hash, &template, &s, sold, lang)
             })
+            .chain(
+                media
+                    .into_iter()
+                    .map(|(hash, media)| renderer.media(hash, &media, lang)),
+            )
             .collect_vec()
     };
 
-    let results = vec![InlineQueryResultArticle::new(
-        moan_hash,
-        "菜喘",
-        InputMessageContent::Text(InputMessageContentText::new(moan)),
-    )]
-    .into_iter()
-    .chain(answers.into_iter().map(|(hash, s)| {
-        InlineQueryResultArticle::new(
-            hash,
-            s.clone(),
-            InputMessageContent::Text(InputMessageContentText::new(s)),
-        )
-    }))
-    .chain(vec![InlineQueryResultArticle::new(
-        sell_stat_hash,
-        "卖菜统计",
-        InputMessageContent::Text(InputMessageContentText::new(sell_stat)),
-    )])
-    .map(InlineQueryResult::Article)
-    .collect_vec();
+    if shared {
+        bot.answer_inline_query(&query.id, results)
+            .cache_time(caching.shared)
+            .await?;
+    } else {
+        bot.answer_inline_query(&query.id, results)
+            .is_personal(true)
+            .cache_time(caching.personal)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Answer [`Mode::Moan`] queries with moans only.
+pub async fn moan_query_handler(
+    query: InlineQuery,
+    bot: AutoSend<Bot>,
+    seller: Arc<Seller>,
+    renderer: Arc<Renderer>,
+    caching: Caching,
+) -> Result<(), Error> {
+    let lang = result_lang(&query, caching.is_shared());
+    let mut rng = caching.rng(&query.query);
+    let results = (0..MOAN_COUNT)
+        .map(|_| seller.moan(&mut rng))
+        .unique()
+        .map(|moan| renderer.moan(format!("{:x}", md5::compute(&moan)), moan, lang))
+        .collect_vec();
+
+    bot.answer_inline_query(&query.id, results)
+        .cache_time(caching.shared)
+        .await?;
+    Ok(())
+}
+
+/// Answer [`Mode::Stat`] and [`Mode::Rank`] queries with a card.
+///
+/// The stat card comes along with the user's personal one.
+#[allow(clippy::too_many_arguments)]
+pub async fn card_query_handler(
+    query: InlineQuery,
+    parsed: ParsedQuery,
+    bot: AutoSend<Bot>,
+    logger: Arc<MongoDBLogger>,
+    seller: Arc<Seller>,
+    renderer: Arc<Renderer>,
+    booking: Arc<RwLock<Booking>>,
+    leaderboard: Arc<Leaderboard>,
+    caching: Caching,
+) -> Result<(), Error> {
+    let lang = Lang::of(&query.from);
+    let cards = if parsed.mode == Mode::Rank {
+        vec![format_rank(&logger.stats(), &leaderboard.names(), lang)]
+    } else {
+        let stats = logger.stats();
+        vec![
+            format_user_stat(&stats, &logger.mask_user(query.from.id), lang),
+            format_summary(stats.summary(), seller.tag_breakdown(&stats), lang),
+        ]
+    };
+    let cards = cards
+        .into_iter()
+        .map(|card| (format!("{:x}", md5::compute(&card)), card))
+        .collect_vec();
+
+    // cards aren't counted into user sell log
+    {
+        let mut booking = booking.write();
+        for (hash, _) in &cards {
+            booking.book_stat(hash.clone());
+        }
+    }
+
+    let results = cards
+        .into_iter()
+        .map(|(hash, card)| {
+            if parsed.mode == Mode::Rank {
+                renderer.rank(hash, card, lang)
+            } else {
+                renderer.stat(hash, card, lang)
+            }
+        })
+        .collect_vec();
 
     bot.answer_inline_query(&query.id, results)
         .is_personal(true)
-        .cache_time(0)
+        .cache_time(caching.personal)
         .await?;
     Ok(())
 }
 
+/// Answer rate limited inline queries with a short notice, cached by Telegram.
+pub async fn limited_query_handler(
+    query: InlineQuery,
+    bot: AutoSend<Bot>,
+    renderer: Arc<Renderer>,
+) -> Result<(), Error> {
+    bot.answer_inline_query(
+        &query.id,
+        vec![renderer.limited(LIMITED_RESULT_ID.to_string(), Lang::of(&query.from))],
+    )
+    .is_personal(true)
+    .cache_time(LIMITED_CACHE_SECS)
+    .await?;
+    Ok(())
+}
+
+/// Drop rate limited commands without answering them, as replying would defeat the limit.
+#[allow(clippy::unused_async)]
+pub async fn limited_command_handler(command: Command) -> Result<(), Error> {
+    debug!("dropped rate limited command: {:?}", command);
+    Ok(())
+}
+
 pub async fn chosen_inline_handler(
     query: ChosenInlineResult,
     logger: Arc<MongoDBLogger>,
+    history: Arc<History>,
     booking: Arc<RwLock<Booking>>,
 ) -> Result<(), Error> {
     let logger = logger.clone();
     let result_id = &query.result_id;
+    if result_id == LIMITED_RESULT_ID {
+        return Ok(());
+    }
 
-    let stat_receipt = booking.write().check_stat(result_id);
-    let maybe_info = if stat_receipt {
-        None
-    } else {
-        let answer = booking
-            .read()
-            .get_answer(result_id.as_str())
-            .unwrap_or_else(|| String::from("-1"));
-        let user = mask_user(query.from.id);
-        Some((answer, user))
-    };
+    let stat_receipt = booking.read().check_stat(result_id);
+    if stat_receipt {
+        return Ok(());
+    }
 
-    if let Some((answer, user)) = maybe_info {
-        logger.log(answer, user).await?;
+    let user = logger.mask_user(query.from.id);
+    let maybe_media = booking.read().get_media(result_id.as_str());
+    if let Some((kind, file_id)) = maybe_media {
+        logger.log_media(kind, file_id, user).await?;
+    } else {
+        let answer = booking.read().get_answer(result_id.as_str());
+        if let Some(answer) = &answer {
+            history.push(user.clone(), answer.clone());
+        }
+        logger
+            .log(answer.unwrap_or_else(|| MOAN_SENTENCE.to_string()), user)
+            .await?;
     }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn message_handler(
     command: Command,
     msg: Message,
     bot: AutoSend<Bot>,
     logger: Arc<MongoDBLogger>,
+    seller: Arc<Seller>,
+    favorites: Arc<Favorites>,
+    suggestions: Arc<SuggestionQueue>,
+    leaderboard: Arc<Leaderboard>,
 ) -> Result<(), Error> {
+    let lang = message_lang(&msg);
     let answer = match command {
         Command::Stat => {
-            let summary = logger.stats().summary();
-
-            let top_sentences_formatted = summary
-                .top_sentences
-                .into_iter()
-                .map(|(s, count)| format!("{}：{} 次", s, count))
-                .join("\n");
-            format!("总共已经有 {} 名迟化人卖了 {} 句菜\n其中最迟的人卖了 {} 句\n\n被卖得最多次的句子：\n{}",
-                    summary.users,
-                    summary.total,
-                    summary.top_user_count,
-                    top_sentences_formatted
+            let stats = logger.stats();
+            format_summary(stats.summary(), seller.tag_breakdown(&stats), lang)
+        }
+        Command::Rank => format_rank(&logger.stats(), &leaderboard.names(), lang),
+        Command::Fav(args) => {
+            let user = match msg.from() {
+                Some(user) => logger.mask_user(user.id),
+                None => return Ok(()),
+            };
+            let (action, text) = args
+                .trim()
+                .split_once(char::is_whitespace)
+                .map_or((args.trim(), ""), |(action, text)| (action, text.trim()));
+            match (action, text) {
+                ("" | "list", _) => {
+                    let favorites = favorites.get(&user);
+                    if favorites.is_empty() {
+                        lang.text("fav.empty").to_string()
+                    } else {
+                        favorites
+                            .iter()
+                            .enumerate()
+                            .map(|(idx, s)| format!("{}. {}", idx + 1, s))
+                            .join("\n")
+                    }
+                }
+                ("add", text) if seller.contains(text) => {
+                    favorites.add(user, text.to_string()).await?;
+                    lang.text("fav.added").to_string()
+                }
+                ("add", _) => lang.text("fav.unknown").to_string(),
+                ("rm", idx) => {
+                    let sentence = idx
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|idx| favorites.get(&user).get(idx.checked_sub(1)?).cloned());
+                    match sentence {
+                        Some(sentence) => {
+                            favorites.remove(user, sentence).await?;
+                            lang.text("fav.removed").to_string()
+                        }
+                        None => lang.text("fav.missing").to_string(),
+                    }
+                }
+                _ => lang.text("fav.usage").to_string(),
+            }
+        }
+        Command::Suggest(text) => match (text.trim(), msg.from()) {
+            ("", _) => lang.text("suggest.usage").to_string(),
+            (text, _) if text.contains('\n') => lang.text("suggest.multiline").to_string(),
+            (text, _) if text.chars().count() > MAX_SUGGESTION_CHARS => {
+                lang.format("suggest.too_long", &[("max", &MAX_SUGGESTION_CHARS)])
+            }
+            (text, Some(user)) => {
+                let submitted = suggestions
+                    .submit(text.to_string(), logger.mask_user(user.id))
+                    .await?;
+                if submitted {
+                    lang.text("suggest.received").to_string()
+                } else {
+                    lang.text("suggest.duplicate").to_string()
+                }
+            }
+            (_, None) => return Ok(()),
+        },
+        Command::Optin(name) => match (name.trim(), msg.from()) {
+            ("", _) => lang.text("optin.usage").to_string(),
+            (name, _) if name.chars().count() > MAX_NAME_CHARS => {
+                lang.format("optin.too_long", &[("max", &MAX_NAME_CHARS)])
+            }
+            (name, Some(user)) => {
+                leaderboard
+                    .opt_in(logger.mask_user(user.id), name.to_string())
+                    .await?;
+                lang.format("optin.done", &[("name", &name)])
+            }
+            (_, None) => return Ok(()),
+        },
+        // only the user may press the buttons in a private chat
+        Command::Forgetme if !msg.chat.is_private() => lang.text("forget.private_only").to_string(),
+        Command::Forgetme => {
+            let button = |key: &'static str, action: &str| {
+                InlineKeyboardButton::callback(
+                    lang.text(key).to_string(),
+                    format!("forget:{}", action),
+                )
+            };
+            bot.send_message(msg.chat.id, lang.text("forget.prompt"))
+                .reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                    button("forget.confirm", "confirm"),
+                    button("forget.cancel", "cancel"),
+                ]]))
+                .await?;
+            return Ok(());
+        }
+        // answered by `info_command_handler`
+        Command::Start(_) | Command::Help | Command::About => return Ok(()),
+        Command::Optout => match msg.from() {
+            Some(user) => {
+                leaderboard.opt_out(&logger.mask_user(user.id)).await?;
+                lang.text("optout.done").to_string()
+            }
+            None => return Ok(()),
+        },
+    };
+
+    bot.send_message(msg.chat.id, answer).await?;
+    Ok(())
+}
+
+/// Answer `/start`, `/help` and `/about`.
+///
+/// The commands listed are the ones in the menu of the chat.
+pub async fn info_command_handler(
+    command: Command,
+    msg: Message,
+    bot: AutoSend<Bot>,
+    corpus: Arc<CorpusClient>,
+    logger: Arc<MongoDBLogger>,
+    admins: Arc<Admins>,
+    me: Me,
+) -> Result<(), Error> {
+    let lang = message_lang(&msg);
+    let menu = if !msg.chat.is_private() {
+        Menu::Group
+    } else if msg.from().is_some_and(|user| admins.contains(user.id)) {
+        Menu::Admin
+    } else {
+        Menu::Private
+    };
+    let answer = match command {
+        Command::Start(_) => {
+            let username = me.user.username.as_deref().unwrap_or_default();
+            format!(
+                "{}\n\n{}",
+                lang.format("start.text", &[("bot", &username)]),
+                menu.help(lang)
+            )
+        }
+        Command::Help => menu.help(lang),
+        Command::About => {
+            let (version, sentences) = {
+                let corpus = corpus.corpus();
+                (corpus.version.clone(), corpus.common.len())
+            };
+            let stats = logger.stats();
+            lang.format(
+                "about.text",
+                &[
+                    ("name", &env!("CARGO_PKG_NAME")),
+                    ("version", &env!("CARGO_PKG_VERSION")),
+                    ("corpus", &version),
+                    ("sentences", &sentences),
+                    ("users", &stats.users.len()),
+                    ("total", &stats.total),
+                ],
+            )
+        }
+        // answered by `message_handler`
+        _ => return Ok(()),
+    };
+
+    bot.send_message(msg.chat.id, answer).await?;
+    Ok(())
+}
+
+fn format_job(name: &str, status: &JobStatus, lang: Lang) -> String {
+    let now = Instant::now();
+    let last_run = status.last_run.map_or_else(
+        || lang.text("job.never_run").to_string(),
+        |last_run| {
+            let result = status.last_error.as_ref().map_or_else(
+                || lang.text("job.succeeded").to_string(),
+                |e| lang.format("job.failed", &[("error", e)]),
+            );
+            lang.format(
+                "job.last_run",
+                &[("secs", &(now - last_run).as_secs()), ("result", &result)],
             )
+        },
+    );
+    let mut lines = vec![lang.format("job.status", &[("name", &name), ("last_run", &last_run)])];
+    if status.failures > 0 {
+        lines.push(lang.format("job.failures", &[("count", &status.failures)]));
+    }
+    if let Some(next_run) = status.next_run {
+        let secs = next_run.saturating_duration_since(now).as_secs();
+        lines.push(lang.format("job.next_run", &[("secs", &secs)]));
+    }
+    lines.join("\n")
+}
+
+fn review_keyboard(id: &ObjectId, lang: Lang) -> InlineKeyboardMarkup {
+    let button = |key: &'static str, action: &str| {
+        InlineKeyboardButton::callback(
+            lang.text(key).to_string(),
+            format!("review:{}:{}", action, id),
+        )
+    };
+    InlineKeyboardMarkup::new(vec![vec![
+        button("review.approve", "approve"),
+        button("review.reject", "reject"),
+        button("review.edit", "edit"),
+    ]])
+}
+
+fn review_text(suggestion: &Suggestion, lang: Lang) -> String {
+    let submitter = &suggestion.submitter[..8.min(suggestion.submitter.len())];
+    format!(
+        "{}\n\n{}",
+        suggestion.text,
+        lang.format("review.submitter", &[("submitter", &submitter)])
+    )
+}
+
+/// Commands only available to admins. Non-admins are filtered out by the dispatcher.
+#[allow(clippy::too_many_arguments)]
+pub async fn admin_command_handler(
+    command: AdminCommand,
+    msg: Message,
+    bot: AutoSend<Bot>,
+    corpus: Arc<CorpusClient>,
+    catalog: Arc<MediaCatalog>,
+    suggestions: Arc<SuggestionQueue>,
+    limits: Arc<Limits>,
+    scheduler: Arc<Scheduler>,
+    alerts: Arc<Alerts>,
+) -> Result<(), Error> {
+    let lang = message_lang(&msg);
+    let answer = match command {
+        AdminCommand::Media(args) => {
+            let args = args.split_whitespace().collect_vec();
+            match args.as_slice() {
+                ["list"] => {
+                    let entries = catalog.entries();
+                    if entries.is_empty() {
+                        lang.text("catalog.empty").to_string()
+                    } else {
+                        entries
+                            .iter()
+                            .map(|entry| {
+                                format!(
+                                    "{} [{}] {}",
+                                    entry.file_unique_id,
+                                    lang.text(entry.media.kind.message_key()),
+                                    entry.media.tags.join(" ")
+                                )
+                            })
+                            .join("\n")
+                    }
+                }
+                ["rm", file_unique_id] => {
+                    if catalog.remove(file_unique_id).await? {
+                        lang.format("catalog.removed", &[("id", file_unique_id)])
+                    } else {
+                        lang.format("catalog.missing", &[("id", file_unique_id)])
+                    }
+                }
+                _ => lang.text("catalog.usage").to_string(),
+            }
+        }
+        AdminCommand::Review => {
+            let pending = suggestions.pending(REVIEW_BATCH).await?;
+            for suggestion in &pending {
+                if let Some(id) = &suggestion.id {
+                    bot.send_message(msg.chat.id, review_text(suggestion, lang))
+                        .reply_markup(review_keyboard(id, lang))
+                        .await?;
+                }
+            }
+            if pending.is_empty() {
+                lang.text("review.empty").to_string()
+            } else {
+                lang.format("review.listed", &[("count", &pending.len())])
+            }
+        }
+        AdminCommand::Metrics => lang.format(
+            "metrics.text",
+            &[
+                ("inline", &limits.inline.hits()),
+                ("command", &limits.command.hits()),
+                ("overloaded", &limits.overloaded()),
+            ],
+        ),
+        AdminCommand::Jobs => scheduler
+            .status()
+            .into_iter()
+            .map(|(name, status)| format_job(name, &status, lang))
+            .join("\n\n"),
+        AdminCommand::Run(name) => match scheduler.run(name.trim()).await {
+            Some(Ok(())) => lang.format("job.run_succeeded", &[("name", &name.trim())]),
+            Some(Err(e)) => lang.format("job.run_failed", &[("name", &name.trim()), ("error", &e)]),
+            None => lang.text("job.run_usage").to_string(),
+        },
+        AdminCommand::Mute(_) if !alerts.enabled() => lang.text("mute.disabled").to_string(),
+        AdminCommand::Mute(minutes) => match minutes.trim() {
+            "" => Some(DEFAULT_MUTE_MINUTES),
+            minutes => minutes.parse().ok(),
+        }
+        .filter(|minutes| *minutes <= MAX_MUTE_MINUTES)
+        .map_or_else(
+            || lang.format("mute.usage", &[("max", &MAX_MUTE_MINUTES)]),
+            |minutes| {
+                alerts.mute(Duration::from_secs(minutes * 60));
+                if minutes == 0 {
+                    lang.text("mute.unmuted").to_string()
+                } else {
+                    lang.format("mute.muted", &[("minutes", &minutes)])
+                }
+            },
+        ),
+        AdminCommand::Export => {
+            let (patch, caption) = {
+                let corpus = corpus.corpus();
+                let file = corpus.format.file_name();
+                (
+                    export_patch(&corpus, &suggestions.approved()),
+                    lang.format("export.caption", &[("file", &file)]),
+                )
+            };
+            match patch {
+                Some(patch) => {
+                    bot.send_document(
+                        msg.chat.id,
+                        InputFile::memory(patch.into_bytes()).file_name("common.patch"),
+                    )
+                    .caption(caption)
+                    .await?;
+                    return Ok(());
+                }
+                None => lang.text("export.empty").to_string(),
+            }
         }
     };
 
     bot.send_message(msg.chat.id, answer).await?;
     Ok(())
 }
+
+/// Handle favorite buttons on sent sentences.
+pub async fn favorite_callback_handler(
+    query: CallbackQuery,
+    bot: AutoSend<Bot>,
+    logger: Arc<MongoDBLogger>,
+    seller: Arc<Seller>,
+    favorites: Arc<Favorites>,
+) -> Result<(), Error> {
+    let sentence = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("fav:"))
+        .and_then(|hash| seller.lookup(hash));
+
+    let lang = Lang::of(&query.from);
+    let notice = match sentence {
+        Some(sentence) => {
+            favorites
+                .add(logger.mask_user(query.from.id), sentence)
+                .await?;
+            lang.text("fav.added")
+        }
+        None => lang.text("fav.gone"),
+    };
+    bot.answer_callback_query(&query.id).text(notice).await?;
+    Ok(())
+}
+
+/// Handle voting buttons on sent sentences.
+pub async fn vote_callback_handler(
+    query: CallbackQuery,
+    bot: AutoSend<Bot>,
+    seller: Arc<Seller>,
+    logger: Arc<MongoDBLogger>,
+) -> Result<(), Error> {
+    let parsed = query.data.as_deref().and_then(|data| {
+        let (action, hash) = data.strip_prefix("vote:")?.split_once(':')?;
+        let vote = match action {
+            "up" => 1,
+            "down" => -1,
+            _ => return None,
+        };
+        Some((vote, seller.lookup(hash)?))
+    });
+
+    let lang = Lang::of(&query.from);
+    let notice = match parsed {
+        Some((vote, sentence)) => {
+            logger
+                .vote(sentence, logger.mask_user(query.from.id), vote)
+                .await?;
+            lang.text("vote.done")
+        }
+        None => lang.text("fav.gone"),
+    };
+    bot.answer_callback_query(&query.id).text(notice).await?;
+    Ok(())
+}
+
+/// Handle the confirmation buttons of `/forgetme`.
+pub async fn forget_callback_handler(
+    query: CallbackQuery,
+    bot: AutoSend<Bot>,
+    logger: Arc<MongoDBLogger>,
+    favorites: Arc<Favorites>,
+    history: Arc<History>,
+    leaderboard: Arc<Leaderboard>,
+    suggestions: Arc<SuggestionQueue>,
+) -> Result<(), Error> {
+    let user = logger.mask_user(query.from.id);
+    let action = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("forget:"));
+
+    let lang = Lang::of(&query.from);
+    let status = match action {
+        Some("confirm") => {
+            logger.forget(&user).await?;
+            favorites.forget(&user).await?;
+            history.forget(&user);
+            leaderboard.opt_out(&user).await?;
+            suggestions.forget(&user).await?;
+            lang.text("forget.done")
+        }
+        Some("cancel") => lang.text("forget.cancelled"),
+        _ => return Ok(()),
+    };
+
+    if let Some(msg) = &query.message {
+        bot.edit_message_text(msg.chat.id, msg.id, status).await?;
+    }
+    bot.answer_callback_query(&query.id).text(status).await?;
+    Ok(())
+}
+
+/// Handle review buttons on pending suggestions.
+pub async fn review_callback_handler(
+    query: CallbackQuery,
+    bot: AutoSend<Bot>,
+    admins: Arc<Admins>,
+    suggestions: Arc<SuggestionQueue>,
+) -> Result<(), Error> {
+    if !admins.contains(query.from.id) {
+        return Err(Error::NotAdmin);
+    }
+
+    let parsed = query.data.as_deref().and_then(|data| {
+        let (action, id) = data.strip_prefix("review:")?.split_once(':')?;
+        Some((action, ObjectId::parse_str(id).ok()?))
+    });
+    let (action, id) = match parsed {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+
+    let lang = Lang::of(&query.from);
+    let (notice, status) = match action {
+        "approve" => (
+            lang.text("review.approved"),
+            suggestions
+                .approve(id, None)
+                .await?
+                .map(|_| lang.text("review.approved_status")),
+        ),
+        "reject" => (
+            lang.text("review.rejected"),
+            suggestions
+                .reject(id)
+                .await?
+                .map(|_| lang.text("review.rejected_status")),
+        ),
+        "edit" => {
+            suggestions.set_editing(query.from.id, id);
+            bot.send_message(query.from.id, lang.text("review.edit_prompt"))
+                .await?;
+            (lang.text("review.edit_notice"), None)
+        }
+        _ => return Ok(()),
+    };
+
+    if let (Some(status), Some(msg)) = (status, &query.message) {
+        let text = format!("{}\n\n{}", msg.text().unwrap_or_default(), status);
+        bot.edit_message_text(msg.chat.id, msg.id, text).await?;
+    }
+    bot.answer_callback_query(&query.id).text(notice).await?;
+    Ok(())
+}
+
+/// Handle non-command messages from admins in private chats.
+///
+/// Forwarded stickers, GIFs and voices are kept pending until the admin replies with their tags.
+/// Text messages also complete suggestion edits started from the review buttons.
+pub async fn admin_message_handler(
+    msg: Message,
+    bot: AutoSend<Bot>,
+    catalog: Arc<MediaCatalog>,
+    suggestions: Arc<SuggestionQueue>,
+) -> Result<(), Error> {
+    let (user, lang) = match msg.from() {
+        Some(user) => (user.id, Lang::of(user)),
+        None => return Ok(()),
+    };
+
+    let media = msg
+        .sticker()
+        .map(|m| (MediaKind::Sticker, &m.file_id, &m.file_unique_id))
+        .or_else(|| {
+            msg.animation()
+                .map(|m| (MediaKind::Gif, &m.file_id, &m.file_unique_id))
+        })
+        .or_else(|| {
+            msg.voice()
+                .map(|m| (MediaKind::Voice, &m.file_id, &m.file_unique_id))
+        });
+
+    if let Some((kind, file_id, file_unique_id)) = media {
+        catalog.set_pending(
+            user,
+            CatalogEntry {
+                file_unique_id: file_unique_id.clone(),
+                media: Media {
+                    kind,
+                    file_id: file_id.clone(),
+                    tags: vec![],
+                },
+            },
+        );
+        let reply = lang.format(
+            "catalog.received",
+            &[("kind", &lang.text(kind.message_key()))],
+        );
+        bot.send_message(msg.chat.id, reply).await?;
+    } else if let Some(text) = msg.text().filter(|text| !text.starts_with('/')) {
+        if let Some(mut entry) = catalog.take_pending(user) {
+            entry.media.tags = text.split_whitespace().map(ToString::to_string).collect();
+            let reply = lang.format(
+                "catalog.added",
+                &[
+                    ("kind", &lang.text(entry.media.kind.message_key())),
+                    ("id", &entry.file_unique_id),
+                ],
+            );
+            catalog.add(entry).await?;
+            bot.send_message(msg.chat.id, reply).await?;
+        } else if let Some(id) = suggestions.take_editing(user) {
+            let reply = match suggestions.approve(id, Some(text.to_string())).await? {
+                Some(suggestion) => lang.format("review.edited", &[("text", &suggestion.text)]),
+                None => lang.text("review.handled").to_string(),
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+    }
+
+    Ok(())
+}
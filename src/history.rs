@@ -0,0 +1,76 @@
+use std::collections::{HashMap, VecDeque};
+
+use parking_lot::RwLock;
+
+/// Recently sent sentences of each (masked) user, most recent first.
+#[derive(Debug)]
+pub struct History {
+    size: usize,
+    recent: RwLock<HashMap<String, VecDeque<String>>>,
+}
+
+impl History {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            recent: RwLock::new(HashMap::new()),
+        }
+    }
+    pub fn push(&self, user: String, sentence: String) {
+        if self.size == 0 {
+            return;
+        }
+        let mut recent = self.recent.write();
+        let history = recent.entry(user).or_default();
+        history.retain(|s| *s != sentence);
+        history.push_front(sentence);
+        history.truncate(self.size);
+    }
+    pub fn recent(&self, user: &str) -> Vec<String> {
+        self.recent
+            .read()
+            .get(user)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    pub fn forget(&self, user: &str) {
+        self.recent.write().remove(user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_sentences_come_first() {
+        let history = History::new(2);
+        history.push("a".to_string(), "甲".to_string());
+        history.push("a".to_string(), "乙".to_string());
+        assert_eq!(history.recent("a"), ["乙", "甲"]);
+        // sending again moves it to the front
+        history.push("a".to_string(), "甲".to_string());
+        assert_eq!(history.recent("a"), ["甲", "乙"]);
+        // the oldest is evicted past the size
+        history.push("a".to_string(), "丙".to_string());
+        assert_eq!(history.recent("a"), ["丙", "甲"]);
+        assert!(history.recent("b").is_empty());
+    }
+
+    #[test]
+    fn nothing_is_kept_without_size() {
+        let history = History::new(0);
+        history.push("a".to_string(), "甲".to_string());
+        assert!(history.recent("a").is_empty());
+    }
+
+    #[test]
+    fn forgotten_users_start_over() {
+        let history = History::new(2);
+        history.push("a".to_string(), "甲".to_string());
+        history.push("b".to_string(), "乙".to_string());
+        history.forget("a");
+        assert!(history.recent("a").is_empty());
+        assert_eq!(history.recent("b"), ["乙"]);
+    }
+}
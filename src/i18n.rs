@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::sync::LazyLock;
+
+use teloxide::types::User;
+use tracing::warn;
+
+// Keys of nested tables are joined by dots, e.g. `stat.title`.
+static CATALOGS: LazyLock<HashMap<Lang, HashMap<String, String>>> = LazyLock::new(|| {
+    Lang::ALL
+        .into_iter()
+        .map(|lang| {
+            let table: toml::Table = lang
+                .source()
+                .parse()
+                .unwrap_or_else(|e| panic!("malformed {} catalog: {}", lang.code(), e));
+            let mut catalog = HashMap::new();
+            flatten("", table, &mut catalog);
+            (lang, catalog)
+        })
+        .collect()
+});
+
+fn flatten(prefix: &str, table: toml::Table, catalog: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::String(text) => {
+                catalog.insert(key, text);
+            }
+            toml::Value::Table(table) => flatten(&key, table, catalog),
+            _ => panic!("catalog entry {} isn't a string", key),
+        }
+    }
+}
+
+/// A language with a message catalog in `locales/`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum Lang {
+    /// Also the fallback of missing entries and unsupported languages.
+    #[default]
+    Zh,
+    En,
+}
+
+impl Lang {
+    pub const ALL: [Self; 2] = [Self::Zh, Self::En];
+
+    /// The IETF language tag of the catalog.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::Zh => "zh",
+            Self::En => "en",
+        }
+    }
+    const fn source(self) -> &'static str {
+        match self {
+            Self::Zh => include_str!("../locales/zh.toml"),
+            Self::En => include_str!("../locales/en.toml"),
+        }
+    }
+    /// The language of an IETF language tag such as `en-US`, matched by its primary subtag.
+    pub fn from_code(code: &str) -> Self {
+        let primary = code.split(['-', '_']).next().unwrap_or_default();
+        Self::ALL
+            .into_iter()
+            .find(|lang| lang.code().eq_ignore_ascii_case(primary))
+            .unwrap_or_default()
+    }
+    /// The language set in the user's Telegram client.
+    pub fn of(user: &User) -> Self {
+        user.language_code
+            .as_deref()
+            .map_or_else(Self::default, Self::from_code)
+    }
+    /// The message of `key`, from the fallback catalog if missing from this one.
+    pub fn text(self, key: &str) -> &str {
+        let text = CATALOGS[&self]
+            .get(key)
+            .or_else(|| CATALOGS[&Self::default()].get(key));
+        match text {
+            Some(text) => text,
+            None => {
+                warn!("missing message {}", key);
+                key
+            }
+        }
+    }
+    /// The message of `key` with its `{name}` placeholders replaced by `args`.
+    ///
+    /// Placeholders are replaced in a single pass, so that values are never expanded.
+    pub fn format(self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let mut rest = self.text(key);
+        let mut text = String::with_capacity(rest.len());
+        while let Some(start) = rest.find('{') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest.find('}').and_then(|end| {
+                let (_, value) = args.iter().find(|(name, _)| *name == &rest[1..end])?;
+                Some((end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    let _ = write!(text, "{}", value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    text.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        text.push_str(rest);
+        text
+    }
+    #[cfg(test)]
+    pub fn keys(self) -> impl Iterator<Item = &'static str> {
+        CATALOGS[&self].keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_are_complete() {
+        let placeholders = |lang: Lang, key| {
+            let mut names: Vec<_> = lang
+                .text(key)
+                .split('{')
+                .skip(1)
+                .filter_map(|s| s.split_once('}').map(|(name, _)| name))
+                .collect();
+            names.sort_unstable();
+            names
+        };
+        let mut keys: Vec<_> = Lang::default().keys().collect();
+        keys.sort_unstable();
+        for lang in Lang::ALL {
+            let mut lang_keys: Vec<_> = lang.keys().collect();
+            lang_keys.sort_unstable();
+            assert_eq!(lang_keys, keys, "keys of {}", lang.code());
+            for key in &keys {
+                assert_eq!(
+                    placeholders(lang, key),
+                    placeholders(Lang::default(), key),
+                    "placeholders of {} in {}",
+                    key,
+                    lang.code()
+                );
+            }
+        }
+
+        assert_eq!(Lang::from_code("en-GB"), Lang::En);
+        assert_eq!(Lang::from_code("zh-hans"), Lang::Zh);
+        // unsupported languages fall back
+        assert_eq!(Lang::from_code("ja"), Lang::Zh);
+        assert_eq!(
+            Lang::En.format("optin.done", &[("name", &"{name}")]),
+            "You're on the leaderboard as “{name}”"
+        );
+    }
+}
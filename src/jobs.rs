@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tracing::{error, Instrument};
+
+use crate::errors::Result;
+use crate::shutdown::Shutdown;
+
+// Retries after a failure start from this delay and double each time, up to the interval.
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+
+type Task = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync>;
+
+/// Outcome of the last run of a job.
+#[derive(Debug, Clone, Default)]
+pub struct JobStatus {
+    pub last_run: Option<Instant>,
+    /// Error of the last run, if it failed.
+    pub last_error: Option<String>,
+    /// Failures in a row.
+    pub failures: u32,
+    pub next_run: Option<Instant>,
+}
+
+struct Job {
+    interval: Duration,
+    jitter: Duration,
+    task: Task,
+    status: Mutex<JobStatus>,
+    // keeps scheduled and triggered runs apart
+    running: tokio::sync::Mutex<()>,
+}
+
+impl Job {
+    fn delay(&self, failures: u32) -> Duration {
+        let base = if failures == 0 {
+            self.interval
+        } else {
+            BASE_BACKOFF
+                .saturating_mul(1 << (failures - 1).min(16))
+                .min(self.interval)
+        };
+        let jitter = rand::thread_rng().gen_range(0.0..=1.0);
+        base + self.jitter.mul_f64(jitter)
+    }
+    async fn run(&self, name: &str) -> Result<()> {
+        let _running = self.running.lock().await;
+        let result = (self.task)().await;
+        let mut status = self.status.lock();
+        status.last_run = Some(Instant::now());
+        match &result {
+            Ok(()) => {
+                status.last_error = None;
+                status.failures = 0;
+            }
+            Err(e) => {
+                error!("job {} failed: {:?}", name, e);
+                status.last_error = Some(e.to_string());
+                status.failures += 1;
+            }
+        }
+        result
+    }
+}
+
+/// Background jobs run periodically until shutdown.
+///
+/// Each run is followed by the job's interval plus a random jitter, or by an exponential
+/// backoff if it failed.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: BTreeMap<&'static str, Arc<Job>>,
+}
+
+impl Scheduler {
+    pub fn register<F, Fut>(
+        &mut self,
+        name: &'static str,
+        interval: Duration,
+        jitter: Duration,
+        task: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let task: Task = Box::new(move || Box::pin(task()));
+        self.jobs.insert(
+            name,
+            Arc::new(Job {
+                interval,
+                jitter,
+                task,
+                status: Mutex::new(JobStatus::default()),
+                running: tokio::sync::Mutex::new(()),
+            }),
+        );
+    }
+    /// Spawn all jobs in the current span. A run in progress is never interrupted by shutdown.
+    pub fn start(&self, shutdown: &Shutdown) {
+        for (&name, job) in &self.jobs {
+            let job = job.clone();
+            let token = shutdown.token();
+            shutdown.track(tokio::spawn(
+                async move {
+                    loop {
+                        let delay = job.delay(job.status.lock().failures);
+                        job.status.lock().next_run = Some(Instant::now() + delay);
+                        tokio::select! {
+                            _ = token.cancelled() => break,
+                            _ = tokio::time::sleep(delay) => {}
+                        }
+                        // errors are kept in the status
+                        let _ = job.run(name).await;
+                    }
+                }
+                .in_current_span(),
+            ));
+        }
+    }
+    /// Status of every job by name.
+    pub fn status(&self) -> Vec<(&'static str, JobStatus)> {
+        self.jobs
+            .iter()
+            .map(|(&name, job)| (name, job.status.lock().clone()))
+            .collect()
+    }
+    /// Run a job now and wait for it. Returns `None` if there's no such job.
+    pub async fn run(&self, name: &str) -> Option<Result<()>> {
+        let (&name, job) = self.jobs.get_key_value(name)?;
+        Some(job.run(name).await)
+    }
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.jobs.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(interval: Duration, jitter: Duration) -> Job {
+        Job {
+            interval,
+            jitter,
+            task: Box::new(|| Box::pin(async { Ok(()) })),
+            status: Mutex::new(JobStatus::default()),
+            running: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_interval() {
+        let job = job(Duration::from_secs(100), Duration::ZERO);
+        assert_eq!(job.delay(0), Duration::from_secs(100));
+        assert_eq!(job.delay(1), Duration::from_secs(30));
+        assert_eq!(job.delay(2), Duration::from_secs(60));
+        assert_eq!(job.delay(3), Duration::from_secs(100));
+        assert_eq!(job.delay(u32::MAX), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        let steady = job(Duration::from_secs(100), Duration::ZERO);
+        let jittery = job(Duration::from_secs(100), Duration::from_secs(10));
+        for failures in [0, 1, 2] {
+            let base = steady.delay(failures);
+            for _ in 0..100 {
+                let delay = jittery.delay(failures);
+                assert!(base <= delay && delay <= base + Duration::from_secs(10));
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::options::UpdateOptions;
+use mongodb::{Collection, Database};
+use parking_lot::{RwLock, RwLockReadGuard};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+
+pub const MAX_NAME_CHARS: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisplayName {
+    pub user: String,
+    pub name: String,
+}
+
+/// Display names chosen by (masked) users who opted into the leaderboard.
+///
+/// Names are kept apart from the `users` stats so that opting out leaves no trace.
+#[derive(Debug)]
+pub struct Leaderboard {
+    coll: Collection<DisplayName>,
+    names: RwLock<HashMap<String, String>>,
+}
+
+impl Leaderboard {
+    pub async fn new(db: &Database) -> Result<Self> {
+        let coll = db.collection("names");
+        let names = coll
+            .find(doc! {"user": {"$exists": true}}, None)
+            .await?
+            .map_ok(|name: DisplayName| (name.user, name.name))
+            .try_collect()
+            .await?;
+        Ok(Self {
+            coll,
+            names: RwLock::new(names),
+        })
+    }
+    /// No names, without reading `db`.
+    #[cfg(test)]
+    pub fn empty(db: &Database) -> Self {
+        Self {
+            coll: db.collection("names"),
+            names: RwLock::default(),
+        }
+    }
+    pub fn names(&self) -> RwLockReadGuard<HashMap<String, String>> {
+        self.names.read()
+    }
+    pub async fn opt_in(&self, user: String, name: String) -> Result<()> {
+        self.coll
+            .update_one(
+                doc! {"user": &user},
+                doc! {"$set": {"name": &name}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        self.names.write().insert(user, name);
+        Ok(())
+    }
+    pub async fn opt_out(&self, user: &str) -> Result<()> {
+        self.coll.delete_one(doc! {"user": user}, None).await?;
+        self.names.write().remove(user);
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::future::join_all;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use teloxide::dispatching::stop_token::AsyncStopToken;
+use teloxide::dispatching::update_listeners::{StatefulListener, UpdateListener};
+use teloxide::types::Update;
+use teloxide::Bot;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::errors::Result;
+
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Where updates come from.
+#[derive(Debug, Clone)]
+pub enum ListenerConfig {
+    Polling,
+    Webhook(WebhookConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Public URL Telegram sends updates to.
+    pub url: Url,
+    /// Path of `url` to serve.
+    pub path: String,
+    pub addr: SocketAddr,
+    /// Telegram echoes it in a header of each update, so that forged ones can be rejected.
+    pub secret_token: Option<String>,
+}
+
+impl WebhookConfig {
+    fn from_env() -> Option<Self> {
+        let base_url: Url = env::var("APP_WEBHOOK_URL")
+            .ok()?
+            .parse()
+            .expect("malformed webhook url");
+        let path = env::var("APP_WEBHOOK_PATH").ok()?;
+        let addr = env::var("APP_BIND_ADDR")
+            .ok()?
+            .parse()
+            .expect("malformed bind address");
+        Some(Self {
+            url: base_url.join(&path).expect("malformed webhook path"),
+            path: format!("/{}", path.trim_start_matches('/')),
+            addr,
+            secret_token: env::var("APP_WEBHOOK_SECRET").ok(),
+        })
+    }
+}
+
+impl WebhookConfig {
+    /// The webhook of one of several bots sharing the server, under a subpath of its name.
+    pub fn for_bot(&self, name: &str) -> Self {
+        let path = format!("{}/{}", self.path.trim_end_matches('/'), name);
+        Self {
+            url: self.url.join(&path).expect("malformed webhook path"),
+            path,
+            ..self.clone()
+        }
+    }
+}
+
+impl ListenerConfig {
+    /// Read `APP_LISTENER`: `polling`, `webhook` or `auto` (the default).
+    ///
+    /// Webhooks need `APP_WEBHOOK_URL`, `APP_WEBHOOK_PATH` and `APP_BIND_ADDR`, and
+    /// `APP_WEBHOOK_SECRET` optionally. In `auto` mode, polling is used unless they're all set.
+    pub fn from_env() -> Self {
+        match env::var("APP_LISTENER").as_deref() {
+            Ok("polling") => Self::Polling,
+            Ok("webhook") => {
+                Self::Webhook(WebhookConfig::from_env().expect("missing webhook config"))
+            }
+            Ok("auto") | Err(_) => WebhookConfig::from_env().map_or(Self::Polling, Self::Webhook),
+            Ok(_) => panic!("unsupported listener"),
+        }
+    }
+}
+
+// teloxide doesn't support the secret token yet, so call `setWebhook` directly.
+async fn set_webhook(bot: &Bot, config: &WebhookConfig) -> Result<()> {
+    let url = bot
+        .api_url()
+        .join(&format!("/bot{}/setWebhook", bot.token()))
+        .expect("malformed api url");
+    let mut payload = json!({ "url": config.url.as_str() });
+    if let Some(secret_token) = &config.secret_token {
+        payload["secret_token"] = json!(secret_token);
+    }
+    bot.client()
+        .post(url)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+struct State<S> {
+    stream: S,
+    stop_token: AsyncStopToken,
+}
+
+impl<S> State<S> {
+    fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+    fn stop_token(&mut self) -> AsyncStopToken {
+        self.stop_token.clone()
+    }
+}
+
+/// Register the webhook of each bot and serve them all, along with the health check for fly.
+///
+/// Returns a listener for each bot, in order. The server stops once they've all stopped.
+pub async fn webhooks(
+    bots: &[(&Bot, WebhookConfig)],
+) -> Result<Vec<impl UpdateListener<Infallible>>> {
+    let mut app = Router::new().route("/health-check", get(|| async { StatusCode::OK }));
+    let mut listeners = vec![];
+    let mut stop_flags = vec![];
+    for (bot, config) in bots {
+        if config.secret_token.is_none() {
+            warn!("no webhook secret set, updates can't be verified");
+        }
+        set_webhook(bot, config).await?;
+
+        let (tx, rx) = unbounded_channel();
+        let secret_token = config.secret_token.clone();
+        app = app.route(
+            &config.path,
+            post(
+                move |Json(update): Json<Update>, headers: HeaderMap| async move {
+                    if let Some(secret_token) = &secret_token {
+                        // compared in constant time, so that the secret can't be guessed by timing
+                        let verified = headers.get(SECRET_TOKEN_HEADER).is_some_and(|value| {
+                            value.as_bytes().ct_eq(secret_token.as_bytes()).into()
+                        });
+                        if !verified {
+                            warn!("rejected update with a wrong secret token");
+                            return StatusCode::UNAUTHORIZED;
+                        }
+                    }
+                    debug!("received update: {:?}", update);
+                    // the dispatcher is shutting down
+                    if tx.send(Ok(update)).is_err() {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    StatusCode::OK
+                },
+            ),
+        );
+        info!(%config.url, "webhook listening for updates");
+
+        let (stop_token, stop_flag) = AsyncStopToken::new_pair();
+        stop_flags.push(stop_flag);
+        listeners.push(StatefulListener::new(
+            State {
+                stream: UnboundedReceiverStream::new(rx),
+                stop_token,
+            },
+            State::stream_mut,
+            State::stop_token,
+        ));
+    }
+
+    let addr = bots.first().map(|(_, config)| config.addr);
+    if let Some(addr) = addr {
+        tokio::spawn(
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(async {
+                    join_all(stop_flags).await;
+                }),
+        );
+        info!(%addr, "webhook server started");
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+    use serde_json::Value;
+    use teloxide::dispatching::update_listeners::AsUpdateStream;
+
+    use super::*;
+    use crate::testing::FakeApi;
+
+    const USER: i64 = 42;
+
+    // An address likely free for a server to bind.
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn message_update(id: i64, text: &str) -> Value {
+        json!({
+            "update_id": id,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": {"id": USER, "type": "private", "first_name": "user"},
+                "from": {"id": USER, "is_bot": false, "first_name": "user"},
+                "text": text
+            }
+        })
+    }
+
+    #[test]
+    fn bots_get_subpaths() {
+        let config = WebhookConfig {
+            url: "https://example.com/hook/".parse().unwrap(),
+            path: String::from("/hook/"),
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            secret_token: None,
+        };
+        let bot = config.for_bot("chi");
+        assert_eq!(bot.path, "/hook/chi");
+        assert_eq!(bot.url.as_str(), "https://example.com/hook/chi");
+    }
+
+    #[tokio::test]
+    async fn bots_share_the_webhook_server() {
+        let apis = [FakeApi::start(&[]), FakeApi::start(&[])];
+        let bots: Vec<_> = apis.iter().map(FakeApi::bot).collect();
+        let addr = free_addr();
+        let config = WebhookConfig {
+            url: format!("http://{}/hook", addr).parse().unwrap(),
+            path: String::from("/hook"),
+            addr,
+            secret_token: None,
+        };
+        let endpoints: Vec<_> = bots
+            .iter()
+            .zip(["chi", "other"])
+            .map(|(bot, name)| (bot.inner(), config.for_bot(name)))
+            .collect();
+        let mut listeners = webhooks(&endpoints).await.unwrap();
+
+        // each bot registers its own subpath
+        assert_eq!(
+            apis[1].calls("setWebhook")[0]["url"],
+            format!("http://{}/hook/other", addr)
+        );
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/hook/other", addr))
+            .json(&message_update(1, "/stat"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let received = tokio::time::timeout(
+            Duration::from_secs(10),
+            Box::pin(listeners[1].as_stream()).next(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(received, Some(Ok(update)) if update.id == 1));
+        // and only that bot receives it
+        let received = tokio::time::timeout(
+            Duration::from_millis(100),
+            Box::pin(listeners[0].as_stream()).next(),
+        )
+        .await;
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn webhook_updates_are_verified() {
+        let api = FakeApi::start(&[]);
+        let bot = api.bot();
+        let addr = free_addr();
+        let config = WebhookConfig {
+            url: format!("http://{}/hook", addr).parse().unwrap(),
+            path: String::from("/hook"),
+            addr,
+            secret_token: Some(String::from("secret")),
+        };
+        let mut listeners = webhooks(&[(bot.inner(), config)]).await.unwrap();
+        assert_eq!(api.calls("setWebhook")[0]["secret_token"], "secret");
+
+        let post = |secret: &'static str, id| {
+            reqwest::Client::new()
+                .post(format!("http://{}/hook", addr))
+                .header("X-Telegram-Bot-Api-Secret-Token", secret)
+                .json(&message_update(id, "/stat"))
+                .send()
+        };
+        assert_eq!(post("forged", 1).await.unwrap().status(), 401);
+        // a prefix of the secret isn't enough
+        assert_eq!(post("secre", 3).await.unwrap().status(), 401);
+        assert_eq!(post("secret", 2).await.unwrap().status(), 200);
+        let received = tokio::time::timeout(
+            Duration::from_secs(10),
+            Box::pin(listeners[0].as_stream()).next(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(received, Some(Ok(update)) if update.id == 2));
+    }
+}
@@ -0,0 +1,178 @@
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use teloxide::dispatching2::UpdateHandler;
+use teloxide::dptree;
+use teloxide::dptree::di::{DependencyMap, DependencySupplier, Injectable};
+use teloxide::dptree::Endpoint;
+use teloxide::types::Update;
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use crate::alert::AlertLayer;
+use crate::errors::Error;
+use crate::stats::MongoDBLogger;
+
+/// How log lines are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Pretty,
+    /// One JSON object per line, with the fields of the current span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Tracing state to flush before exiting.
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Export the remaining spans.
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!("unable to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+fn otlp_provider(endpoint: String) -> opentelemetry_sdk::trace::SdkTracerProvider {
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .expect("unable to build otlp exporter");
+    SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build()
+}
+
+/// Log to stdout in `format` and forward errors to `alerts`, for events allowed by `filter`.
+///
+/// Spans are also exported to the OTLP collector at `otlp_endpoint` if built with `otel`.
+pub fn init(
+    format: LogFormat,
+    filter: EnvFilter,
+    alerts: AlertLayer,
+    otlp_endpoint: Option<String>,
+) -> Telemetry {
+    let output = match format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    #[allow(unused_mut)]
+    let mut layers = vec![output, alerts.boxed()];
+
+    #[cfg(feature = "otel")]
+    let telemetry = Telemetry {
+        provider: otlp_endpoint.clone().map(otlp_provider),
+    };
+    #[cfg(feature = "otel")]
+    if let Some(provider) = &telemetry.provider {
+        use opentelemetry::trace::TracerProvider;
+
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        layers.push(tracing_opentelemetry::layer().with_tracer(tracer).boxed());
+    }
+    #[cfg(not(feature = "otel"))]
+    let telemetry = Telemetry::default();
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layers)
+        .init();
+
+    #[cfg(not(feature = "otel"))]
+    if otlp_endpoint.is_some() {
+        tracing::warn!("built without the otel feature, spans aren't exported");
+    }
+    telemetry
+}
+
+/// Run `handler` in a span of the update recording its ID and masked user, so that filtering,
+/// handling and reporting errors are all logged in it.
+pub fn in_update_span(handler: UpdateHandler<Error>) -> UpdateHandler<Error> {
+    dptree::from_fn(move |deps: DependencyMap, cont| {
+        let handler = handler.clone();
+        let update: Arc<Update> = deps.get();
+        let logger: Arc<Arc<MongoDBLogger>> = deps.get();
+        let span = info_span!(
+            "update",
+            id = update.id,
+            handler = field::Empty,
+            user = field::Empty
+        );
+        if let Some(user) = update.user() {
+            span.record("user", logger.mask_user(user.id).as_str());
+        }
+        async move {
+            match handler.dispatch(deps).await {
+                ControlFlow::Continue(deps) => cont(deps).await,
+                done => done,
+            }
+        }
+        .instrument(span)
+    })
+}
+
+/// An endpoint running `handler`, naming it in the span of the update and logging how long it
+/// took.
+pub fn traced<F, FnArgs>(
+    name: &'static str,
+    handler: F,
+) -> Endpoint<'static, DependencyMap, Result<(), Error>>
+where
+    F: Injectable<DependencyMap, Result<(), Error>, FnArgs> + Send + Sync + 'static,
+{
+    let handler = dptree::endpoint(handler);
+    dptree::from_fn(move |deps: DependencyMap, _cont| {
+        let handler = handler.clone();
+        async move {
+            Span::current().record("handler", name);
+            let start = Instant::now();
+            let result = handler.dispatch(deps).await;
+            info!(
+                latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+                "update handled"
+            );
+            result
+        }
+    })
+}
@@ -1,4 +1,5 @@
 #![allow(
+    mismatched_lifetime_syntaxes,
     clippy::non_ascii_literal,
     clippy::cast_lossless,
     clippy::module_name_repetitions
@@ -10,119 +11,563 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use futures_util::future::join_all;
+use itertools::Itertools;
 use mongodb::Client;
 use parking_lot::RwLock;
-use teloxide::dispatching2::{Dispatcher, HandlerExt, UpdateFilterExt};
-use teloxide::error_handlers::LoggingErrorHandler;
-use teloxide::requests::RequesterExt;
-use teloxide::types::Update;
+use teloxide::adaptors::AutoSend;
+use teloxide::dispatching::update_listeners;
+use teloxide::dispatching2::{Dispatcher, HandlerExt, UpdateFilterExt, UpdateHandler};
+use teloxide::requests::{Requester, RequesterExt};
+use teloxide::types::{CallbackQuery, InlineQuery, Message, Update};
 use teloxide::utils::command::BotCommand;
 use teloxide::{dptree, Bot};
-use teloxide_listener::Listener;
-use tracing::error;
+use tracing::{info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 use url::Url;
 
-use errors::Result;
+use errors::{Error, Result};
 
+use crate::admin::Admins;
+use crate::alert::{alerts, Alerts};
 use crate::booking::Booking;
+use crate::bots::BotConfig;
+use crate::cache::Caching;
+use crate::catalog::MediaCatalog;
 use crate::corpus::CorpusClient;
-use crate::handlers::{chosen_inline_handler, inline_query_handler, message_handler};
+use crate::favorites::Favorites;
+use crate::handlers::{
+    admin_command_handler, admin_message_handler, card_query_handler, chosen_inline_handler,
+    favorite_callback_handler, favorite_query_handler, forget_callback_handler,
+    info_command_handler, inline_query_handler, limited_command_handler, limited_query_handler,
+    message_handler, moan_query_handler, review_callback_handler, tag_query_handler,
+    vote_callback_handler,
+};
+use crate::history::History;
+use crate::jobs::Scheduler;
+use crate::leaderboard::Leaderboard;
+use crate::listener::{webhooks, ListenerConfig};
+use crate::logging::{in_update_span, traced, LogFormat};
+use crate::markup::Format;
 use crate::migrate::Migrator;
-use crate::seller::Seller;
+use crate::query::{Mode, ParsedQuery};
+use crate::ratelimit::{Limits, Quota};
+use crate::rehash::Rehasher;
+use crate::render::{Buttons, Renderer};
+use crate::report::{with_update, Reporter};
+use crate::seller::{Selection, Seller};
+use crate::shutdown::Shutdown;
 use crate::stats::MongoDBLogger;
-use crate::utils::mask_user;
+use crate::suggestion::SuggestionQueue;
 
+mod admin;
+mod alert;
 mod booking;
+mod bots;
+mod cache;
+mod catalog;
 mod corpus;
 mod errors;
+mod favorites;
 mod handlers;
+mod history;
+mod i18n;
+mod jobs;
+mod leaderboard;
+mod listener;
+mod logging;
+mod markup;
+mod menu;
 mod migrate;
+mod query;
+mod ratelimit;
+mod rehash;
+mod render;
+mod report;
 mod seller;
+mod shutdown;
 mod stats;
+mod suggestion;
+mod template;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
 mod utils;
 
 const UPD_INTERVAL_SECS: u64 = 60 * 60;
+const JOB_JITTER_SECS: u64 = 5 * 60;
+const DEFAULT_HISTORY_SIZE: usize = 10;
+// Both within `kill_timeout` in fly.toml.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+const JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+// Matches `hard_limit` in fly.toml.
+const DEFAULT_CONCURRENCY: usize = 25;
+const ALERT_BATCH_DELAY: Duration = Duration::from_secs(5);
+const ALERT_DEDUP_WINDOW: Duration = Duration::from_secs(10 * 60);
 
+// Read a rate limit quota from `APP_<name>_RATE` and `APP_<name>_BURST`.
+fn quota_from_env(name: &str, default: Quota) -> Quota {
+    let parse = |var: &str, default: f64| {
+        env::var(format!("APP_{}_{}", name, var)).map_or(default, |value| {
+            value.parse().expect("malformed rate limit")
+        })
+    };
+    Quota {
+        rate: parse("RATE", default.rate),
+        burst: parse("BURST", default.burst),
+    }
+}
+
+// Descriptions are catalog keys, described in the user's language by `menu`.
 #[derive(Debug, Clone, BotCommand)]
 #[command(rename = "lowercase")]
 pub enum Command {
+    // with the payload of deep links, if any
+    #[command(description = "command.start")]
+    Start(String),
+    #[command(description = "command.help")]
+    Help,
+    #[command(description = "command.about")]
+    About,
+    #[command(description = "command.stat")]
     Stat,
+    #[command(description = "command.rank")]
+    Rank,
+    #[command(description = "command.fav")]
+    Fav(String),
+    #[command(description = "command.suggest")]
+    Suggest(String),
+    #[command(description = "command.optin")]
+    Optin(String),
+    #[command(description = "command.optout")]
+    Optout,
+    #[command(description = "command.forgetme")]
+    Forgetme,
+}
+
+impl Command {
+    /// Commands about the bot itself, answered by `info_command_handler`.
+    pub const fn is_info(&self) -> bool {
+        matches!(self, Self::Start(_) | Self::Help | Self::About)
+    }
+}
+
+#[derive(Debug, Clone, BotCommand)]
+#[command(rename = "lowercase")]
+pub enum AdminCommand {
+    #[command(description = "command.media")]
+    Media(String),
+    #[command(description = "command.review")]
+    Review,
+    #[command(description = "command.export")]
+    Export,
+    #[command(description = "command.metrics")]
+    Metrics,
+    #[command(description = "command.jobs")]
+    Jobs,
+    #[command(description = "command.run")]
+    Run(String),
+    #[command(description = "command.mute")]
+    Mute(String),
+}
+
+fn callback_prefix(query: &CallbackQuery, prefix: &str) -> bool {
+    query
+        .data
+        .as_deref()
+        .is_some_and(|data| data.starts_with(prefix))
+}
+
+/// The dispatching tree of all handlers.
+fn schema() -> UpdateHandler<Error> {
+    let handlers = dptree::entry()
+        .branch(
+            Update::filter_inline_query()
+                .branch(
+                    dptree::filter_map(|query: InlineQuery, limits: Arc<Limits>| {
+                        limits.admit(&limits.inline, query.from.id)
+                    })
+                    .chain(dptree::filter_map(|query: InlineQuery| {
+                        Some(query::parse(&query.query))
+                    }))
+                    .branch(
+                        dptree::filter(|parsed: ParsedQuery| parsed.mode == Mode::Favorite)
+                            .chain(traced("favorite_query", favorite_query_handler)),
+                    )
+                    .branch(
+                        dptree::filter(|parsed: ParsedQuery| parsed.mode == Mode::Tag)
+                            .chain(traced("tag_query", tag_query_handler)),
+                    )
+                    .branch(
+                        dptree::filter(|parsed: ParsedQuery| parsed.mode == Mode::Moan)
+                            .chain(traced("moan_query", moan_query_handler)),
+                    )
+                    .branch(
+                        dptree::filter(|parsed: ParsedQuery| {
+                            matches!(parsed.mode, Mode::Stat | Mode::Rank)
+                        })
+                        .chain(traced("card_query", card_query_handler)),
+                    )
+                    .branch(traced("inline_query", inline_query_handler)),
+                )
+                .branch(traced("limited_query", limited_query_handler)),
+        )
+        .branch(
+            Update::filter_chosen_inline_result()
+                .chain(traced("chosen_inline", chosen_inline_handler)),
+        )
+        .branch(
+            Update::filter_message()
+                .filter_command::<Command>()
+                .branch(
+                    dptree::filter_map(|msg: Message, limits: Arc<Limits>| {
+                        limits.admit(&limits.command, msg.from()?.id)
+                    })
+                    .branch(
+                        dptree::filter(|command: Command| command.is_info())
+                            .chain(traced("info_command", info_command_handler)),
+                    )
+                    .branch(traced("message", message_handler)),
+                )
+                .branch(traced("limited_command", limited_command_handler)),
+        )
+        .branch(
+            Update::filter_message()
+                .filter_command::<AdminCommand>()
+                .chain(dptree::filter(|msg: Message, admins: Arc<Admins>| {
+                    msg.from().is_some_and(|user| admins.contains(user.id))
+                }))
+                .chain(traced("admin_command", admin_command_handler)),
+        )
+        .branch(
+            Update::filter_message()
+                .chain(dptree::filter(|msg: Message, admins: Arc<Admins>| {
+                    msg.chat.is_private() && msg.from().is_some_and(|user| admins.contains(user.id))
+                }))
+                .chain(traced("admin_message", admin_message_handler)),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .branch(
+                    dptree::filter(|query: CallbackQuery| callback_prefix(&query, "vote:"))
+                        .chain(traced("vote_callback", vote_callback_handler)),
+                )
+                .branch(
+                    dptree::filter(|query: CallbackQuery| callback_prefix(&query, "fav:"))
+                        .chain(traced("favorite_callback", favorite_callback_handler)),
+                )
+                .branch(
+                    dptree::filter(|query: CallbackQuery| callback_prefix(&query, "review:"))
+                        .chain(traced("review_callback", review_callback_handler)),
+                )
+                .branch(
+                    dptree::filter(|query: CallbackQuery| callback_prefix(&query, "forget:"))
+                        .chain(traced("forget_callback", forget_callback_handler)),
+                ),
+        );
+    in_update_span(with_update(handlers))
+}
+
+// Settings shared by all bots.
+struct Settings {
+    parse_mode: Format,
+    thumb_base_url: Option<Url>,
+    selection: Selection,
+    buttons: Buttons,
+    history_size: usize,
+    admins: Arc<Admins>,
+    // per user across bots, so that the concurrency cap is the process's
+    limits: Arc<Limits>,
+    caching: Caching,
+    alerts: Arc<Alerts>,
+    mask_key: Option<Vec<u8>>,
+}
+
+// A bot ready to dispatch updates.
+struct Tenant {
+    bot: AutoSend<Bot>,
+    dispatcher: Dispatcher<AutoSend<Bot>, Error>,
+    logger: Arc<MongoDBLogger>,
+}
+
+// Load the corpus and data of a bot, and start its background jobs.
+async fn start_bot(
+    config: &BotConfig,
+    settings: &Settings,
+    client: &Client,
+    shutdown: &Shutdown,
+) -> Result<Tenant> {
+    let db = client.database(&config.db);
+    let corpus = Arc::new(CorpusClient::new_with_url(&config.corpus_url).await?);
+    let catalog = Arc::new(MediaCatalog::new(&db).await?);
+    let suggestions = Arc::new(SuggestionQueue::new(&db).await?);
+    let seller = Arc::new(Seller::new(
+        corpus.clone(),
+        catalog.clone(),
+        suggestions.clone(),
+        settings.selection,
+        config.moan.clone(),
+    ));
+    let renderer = Arc::new(Renderer::new(
+        settings.parse_mode,
+        settings.thumb_base_url.clone(),
+        settings.buttons,
+    ));
+
+    let favorites = Arc::new(Favorites::new(&db).await?);
+    let leaderboard = Arc::new(Leaderboard::new(&db).await?);
+    // legacy users differ between databases, so each bot masks its own
+    let logger = Arc::new(MongoDBLogger::new(db, settings.mask_key.clone()).await?);
+
+    let history = Arc::new(History::new(settings.history_size));
+
+    let booking = Arc::new(RwLock::new(Booking::new(Duration::from_secs(
+        settings.caching.personal.into(),
+    ))));
+
+    let mut scheduler = Scheduler::default();
+    {
+        let corpus = corpus.clone();
+        scheduler.register(
+            "corpus",
+            Duration::from_secs(UPD_INTERVAL_SECS),
+            Duration::from_secs(JOB_JITTER_SECS),
+            move || {
+                let corpus = corpus.clone();
+                async move { corpus.update().await }
+            },
+        );
+    }
+    {
+        let logger = logger.clone();
+        scheduler.register(
+            "stats",
+            Duration::from_secs(UPD_INTERVAL_SECS),
+            Duration::from_secs(JOB_JITTER_SECS),
+            move || {
+                let logger = logger.clone();
+                async move { logger.sync().await }
+            },
+        );
+    }
+    {
+        // tell the jobs of different bots apart
+        let _span = info_span!("bot", name = %config.name).entered();
+        scheduler.start(shutdown);
+    }
+    let scheduler = Arc::new(scheduler);
+
+    let bot = Bot::new(&config.token).auto_send();
+    // the username is shown by `/start`, and doesn't change while running
+    let me = bot.get_me().await?;
+    // the bot works without menus all the same
+    if let Err(e) = menu::register(&bot, &settings.admins).await {
+        warn!("unable to register the command menus: {}", e);
+    }
+    let dispatcher = Dispatcher::builder(bot.clone(), schema())
+        .dependencies(dptree::deps![
+            corpus,
+            seller,
+            renderer,
+            logger.clone(),
+            history,
+            favorites,
+            booking,
+            settings.admins.clone(),
+            catalog,
+            suggestions,
+            leaderboard,
+            settings.limits.clone(),
+            settings.caching,
+            scheduler,
+            settings.alerts.clone(),
+            me
+        ])
+        .error_handler(Reporter::new(bot.clone()))
+        .build();
+
+    Ok(Tenant {
+        bot,
+        dispatcher,
+        logger,
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().init();
-
-    let base_url = Url::from_str(
-        env::var("APP_CORPUS_URL")
-            .expect("missing corpus url")
-            .as_str(),
-    )
-    .expect("malformed url");
+    let admin_chat = env::var("APP_ADMIN_CHAT")
+        .ok()
+        .map(|chat| chat.parse().expect("malformed admin chat"));
+    let (alert_layer, alerts) = alerts(admin_chat, ALERT_BATCH_DELAY, ALERT_DEDUP_WINDOW);
+    let log_format = env::var("APP_LOG_FORMAT").map_or(LogFormat::Text, |format| {
+        format.parse().expect("unsupported log format")
+    });
+    let log_filter = EnvFilter::try_from_env("APP_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let telemetry = logging::init(
+        log_format,
+        log_filter,
+        alert_layer,
+        env::var("APP_OTLP_ENDPOINT").ok(),
+    );
+
+    let bots = BotConfig::from_env();
+    let parse_mode = env::var("APP_PARSE_MODE").map_or(Format::Html, |mode| {
+        mode.parse().expect("unsupported parse mode")
+    });
+    let thumb_base_url = env::var("APP_THUMB_BASE_URL")
+        .ok()
+        .map(|url| Url::from_str(url.as_str()).expect("malformed thumb url"));
+    let selection = match env::var("APP_SELECTION").as_deref() {
+        Ok("weighted") => {
+            Selection::weighted(env::var("APP_EXPLORATION").map_or(0.2, |exploration| {
+                exploration.parse().expect("malformed exploration factor")
+            }))
+            .expect("exploration factor out of [0, 1]")
+        }
+        Ok("uniform") | Err(_) => Selection::Uniform,
+        Ok(_) => panic!("unsupported selection mode"),
+    };
+    let buttons = Buttons {
+        vote: env::var("APP_VOTING").is_ok(),
+        favorite: env::var("APP_FAV_BUTTON").is_ok(),
+    };
+    let history_size = env::var("APP_HISTORY_SIZE").map_or(DEFAULT_HISTORY_SIZE, |size| {
+        size.parse().expect("malformed history size")
+    });
+    let admins = Arc::new(env::var("APP_ADMINS").map_or_else(
+        |_| Admins::default(),
+        |admins| admins.parse().expect("malformed admin list"),
+    ));
+    let listener = ListenerConfig::from_env();
+    let mask_key = env::var("APP_MASK_KEY").ok().map(String::into_bytes);
+    if mask_key.is_none() {
+        warn!("no mask key set, users are masked by unsalted hashes");
+    }
+    let caching = Caching {
+        shared: env::var("APP_CACHE_TIME")
+            .map_or(0, |secs| secs.parse().expect("malformed cache time")),
+        personal: env::var("APP_PERSONAL_CACHE_TIME").map_or(0, |secs| {
+            secs.parse().expect("malformed personal cache time")
+        }),
+    };
+    let limits = Arc::new(Limits::new(
+        quota_from_env(
+            "INLINE",
+            Quota {
+                rate: 2.0,
+                burst: 10.0,
+            },
+        ),
+        quota_from_env(
+            "COMMAND",
+            Quota {
+                rate: 0.2,
+                burst: 5.0,
+            },
+        ),
+        env::var("APP_CONCURRENCY").map_or(DEFAULT_CONCURRENCY, |concurrency| {
+            concurrency.parse().expect("malformed concurrency limit")
+        }),
+    ));
     let mongodb_uri = env::var("APP_MONGODB_URI").expect("missing mongodb url");
-    let mongodb_db_name = env::var("APP_MONGODB_DBNAME").expect("missing mongodb dbname");
     let client = Client::with_uri_str(mongodb_uri).await?;
-    let db = client.database(mongodb_db_name.as_str());
 
     let migrate_log = env::var("APP_MIGRATE_LOG").ok();
     if let Some(migrate_log) = migrate_log {
+        assert!(bots.len() == 1, "migrating a log needs a single bot");
         let f = File::open(migrate_log)?;
         let migrator = Migrator::from_reader(f)?;
-        migrator.migrate(db).await?;
+        migrator.migrate(client.database(&bots[0].db)).await?;
         return Ok(());
     }
 
-    let corpus = Arc::new(CorpusClient::new_with_url(&base_url).await?);
-    let seller = Arc::new(Seller::new(corpus.clone()));
-
-    let logger = Arc::new(MongoDBLogger::new(db).await?);
-
-    let booking = Arc::new(RwLock::new(Booking::default()));
+    let rehash_users = env::var("APP_REHASH_USERS").ok();
+    if let Some(rehash_users) = rehash_users {
+        let mask_key = mask_key.expect("missing mask key");
+        let rehasher = Rehasher::from_reader(File::open(rehash_users)?)?;
+        for bot in &bots {
+            let migrated = rehasher.rehash(client.database(&bot.db), &mask_key).await?;
+            info!("rehashed {} users of {}", migrated, bot.name);
+        }
+        return Ok(());
+    }
 
-    {
-        let corpus = corpus.clone();
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(UPD_INTERVAL_SECS)).await;
-                if let Err(e) = corpus.update().await {
-                    error!("unable to update corpus: {:?}", e);
-                }
-            }
-        });
+    let settings = Settings {
+        parse_mode,
+        thumb_base_url,
+        selection,
+        buttons,
+        history_size,
+        admins,
+        limits,
+        caching,
+        alerts: alerts.clone(),
+        mask_key,
+    };
+    let shutdown = Shutdown::default();
+    let mut tenants = vec![];
+    for config in &bots {
+        tenants.push(start_bot(config, &settings, &client, &shutdown).await?);
     }
+    // alerts from all bots go through the first one
+    alerts.start(tenants[0].bot.clone(), &shutdown);
 
-    {
-        let logger = logger.clone();
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(UPD_INTERVAL_SECS)).await;
-                if let Err(e) = logger.sync().await {
-                    error!("unable to sync logger: {:?}", e);
-                }
+    shutdown.listen(
+        tenants
+            .iter()
+            .map(|tenant| tenant.dispatcher.shutdown_token())
+            .collect(),
+    );
+    match listener {
+        ListenerConfig::Polling => {
+            let mut runs = vec![];
+            for (tenant, config) in tenants.iter_mut().zip(&bots) {
+                // polling doesn't work while a webhook is set
+                tenant.bot.delete_webhook().await?;
+                let listener = update_listeners::polling_default(tenant.bot.clone()).await;
+                runs.push(
+                    shutdown
+                        .dispatch(&mut tenant.dispatcher, listener, DRAIN_TIMEOUT)
+                        .instrument(info_span!("bot", name = %config.name)),
+                );
             }
-        });
+            join_all(runs).await;
+        }
+        ListenerConfig::Webhook(config) => {
+            let endpoints = tenants
+                .iter()
+                .zip(&bots)
+                .map(|(tenant, bot)| {
+                    let config = if bots.len() == 1 {
+                        config.clone()
+                    } else {
+                        config.for_bot(&bot.name)
+                    };
+                    (tenant.bot.inner(), config)
+                })
+                .collect_vec();
+            let listeners = webhooks(&endpoints).await?;
+            let runs =
+                tenants
+                    .iter_mut()
+                    .zip(listeners)
+                    .zip(&bots)
+                    .map(|((tenant, listener), config)| {
+                        shutdown
+                            .dispatch(&mut tenant.dispatcher, listener, DRAIN_TIMEOUT)
+                            .instrument(info_span!("bot", name = %config.name))
+                    });
+            join_all(runs).await;
+        }
     }
-
-    let bot = Bot::from_env().auto_send();
-    let listener = Listener::from_env_with_prefix("APP_")
-        .build(bot.clone())
-        .await;
-    Dispatcher::builder(
-        bot,
-        dptree::entry()
-            .branch(Update::filter_inline_query().endpoint(inline_query_handler))
-            .branch(Update::filter_chosen_inline_result().endpoint(chosen_inline_handler))
-            .branch(
-                Update::filter_message()
-                    .filter_command::<Command>()
-                    .branch(dptree::endpoint(message_handler)),
-            ),
-    )
-    .dependencies(dptree::deps![seller, logger, booking])
-    .build()
-    .setup_ctrlc_handler()
-    .dispatch_with_listener(listener, LoggingErrorHandler::new())
-    .await;
+    shutdown.cancel();
+    // stats logged by handlers dropped after the drain timeout are still being written
+    let flushes = tenants
+        .iter()
+        .map(|tenant| tenant.logger.flush(JOIN_TIMEOUT));
+    tokio::join!(join_all(flushes), shutdown.join(JOIN_TIMEOUT));
+    telemetry.shutdown();
 
     Ok(())
 }
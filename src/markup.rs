@@ -0,0 +1,254 @@
+use std::str::FromStr;
+
+use teloxide::types::ParseMode;
+use teloxide::utils::{html, markdown};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Style {
+    Plain,
+    Bold,
+    Italic,
+    Strike,
+    Spoiler,
+    Code,
+}
+
+const MARKS: [(&str, Style); 5] = [
+    ("**", Style::Bold),
+    ("__", Style::Italic),
+    ("~~", Style::Strike),
+    ("||", Style::Spoiler),
+    ("`", Style::Code),
+];
+
+const ESCAPABLE: [char; 6] = ['\\', '*', '_', '~', '|', '`'];
+
+/// Output format of corpus markup.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    Html,
+    MarkdownV2,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "html" => Ok(Self::Html),
+            "markdownv2" => Ok(Self::MarkdownV2),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Format> for ParseMode {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Html => Self::Html,
+            Format::MarkdownV2 => Self::MarkdownV2,
+        }
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek().is_some_and(|c| ESCAPABLE.contains(c)) {
+            output.extend(chars.next());
+        } else {
+            output.push(c);
+        }
+    }
+    output
+}
+
+// Find the first unescaped occurrence of `mark` in `s`.
+fn find_close(s: &str, mark: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (idx, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if s[idx..].starts_with(mark) {
+            return Some(idx);
+        }
+    }
+    None
+}
+
+// Split corpus markup into styled segments. Unmatched marks are kept as-is.
+fn parse(s: &str) -> Vec<(Style, String)> {
+    let mut segments = vec![];
+    let mut plain = String::new();
+    let mut rest = s;
+    'outer: while let Some(c) = rest.chars().next() {
+        if c == '\\' {
+            if let Some(next) = rest[1..].chars().next().filter(|c| ESCAPABLE.contains(c)) {
+                plain.push(next);
+                rest = &rest[1 + next.len_utf8()..];
+                continue;
+            }
+        }
+        for (mark, style) in MARKS {
+            if let Some(inner) = rest.strip_prefix(mark) {
+                match find_close(inner, mark) {
+                    Some(end) if end > 0 => {
+                        if !plain.is_empty() {
+                            segments.push((Style::Plain, std::mem::take(&mut plain)));
+                        }
+                        segments.push((style, unescape(&inner[..end])));
+                        rest = &inner[end + mark.len()..];
+                    }
+                    _ => {
+                        plain.push_str(mark);
+                        rest = inner;
+                    }
+                }
+                continue 'outer;
+            }
+        }
+        plain.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    if !plain.is_empty() {
+        segments.push((Style::Plain, plain));
+    }
+    segments
+}
+
+/// Escape text so that it's rendered literally when embedded into corpus markup.
+pub fn escape(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for c in s.chars() {
+        if ESCAPABLE.contains(&c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+    output
+}
+
+/// Strip all markup from `s`.
+pub fn to_plain(s: &str) -> String {
+    parse(s).into_iter().map(|(_, text)| text).collect()
+}
+
+// MarkdownV2 also requires backslashes to be escaped, which `markdown::escape` leaves alone.
+fn escape_markdown(s: &str) -> String {
+    markdown::escape(&s.replace('\\', r"\\"))
+}
+
+/// Render corpus markup into Telegram formatted text.
+pub fn render(s: &str, format: Format) -> String {
+    let mut output = String::new();
+    let pieces = parse(s).into_iter().map(|(style, text)| match format {
+        Format::Html => match style {
+            Style::Plain => html::escape(&text),
+            Style::Bold => html::bold(&html::escape(&text)),
+            Style::Italic => html::italic(&html::escape(&text)),
+            Style::Strike => html::strike(&html::escape(&text)),
+            Style::Spoiler => format!("<tg-spoiler>{}</tg-spoiler>", html::escape(&text)),
+            Style::Code => html::code_inline(&text),
+        },
+        Format::MarkdownV2 => match style {
+            Style::Plain => escape_markdown(&text),
+            Style::Bold => markdown::bold(&escape_markdown(&text)),
+            Style::Italic => markdown::italic(&escape_markdown(&text)),
+            Style::Strike => markdown::strike(&escape_markdown(&text)),
+            Style::Spoiler => format!("||{}||", escape_markdown(&text)),
+            Style::Code => markdown::code_inline(&text),
+        },
+    });
+    for piece in pieces {
+        // Telegram takes `__` for underline, so adjacent italics are separated by an ignored `\r`
+        if format == Format::MarkdownV2 && output.ends_with('_') && piece.starts_with('_') {
+            output.push('\r');
+        }
+        output.push_str(&piece);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn both(s: &str) -> (String, String) {
+        (render(s, Format::Html), render(s, Format::MarkdownV2))
+    }
+
+    #[test]
+    fn marks_are_rendered() {
+        assert_eq!(
+            both("**粗**__斜__~~删~~||剧透||`码`"),
+            (
+                String::from(
+                    "<b>粗</b><i>斜</i><s>删</s><tg-spoiler>剧透</tg-spoiler><code>码</code>"
+                ),
+                String::from("*粗*_斜_~删~||剧透||`码`"),
+            )
+        );
+    }
+
+    #[test]
+    fn adjacent_marks_are_separate() {
+        assert_eq!(
+            both("__斜____体__**粗****体**"),
+            (
+                String::from("<i>斜</i><i>体</i><b>粗</b><b>体</b>"),
+                String::from("_斜_\r_体_*粗**体*"),
+            )
+        );
+    }
+
+    #[test]
+    fn nested_marks_are_literal() {
+        assert_eq!(
+            both("**粗__斜__**"),
+            (
+                String::from("<b>粗__斜__</b>"),
+                String::from(r"*粗\_\_斜\_\_*"),
+            )
+        );
+    }
+
+    #[test]
+    fn unterminated_and_empty_marks_are_literal() {
+        assert_eq!(
+            both("**粗 `码"),
+            (String::from("**粗 `码"), String::from(r"\*\*粗 \`码"))
+        );
+        assert_eq!(to_plain("****"), "****");
+        assert_eq!(to_plain("a||b"), "a||b");
+    }
+
+    #[test]
+    fn escaped_marks_are_literal() {
+        assert_eq!(to_plain(r"\*\*a\*\* \\ \n"), r"**a** \ \n");
+        assert_eq!(
+            both(r"**a\*\*b**"),
+            (String::from("<b>a**b</b>"), String::from(r"*a\*\*b*"))
+        );
+    }
+
+    #[test]
+    fn escaped_text_is_literal() {
+        let text = r"<b>&amp; _*|~`\";
+        assert_eq!(to_plain(&escape(text)), text);
+        assert_eq!(
+            both(&escape(text)),
+            (
+                String::from(r"&lt;b&gt;&amp;amp; _*|~`\"),
+                String::from(r"<b\>&amp; \_\*\|\~\`\\"),
+            )
+        );
+        // also inside marks
+        assert_eq!(
+            render(&format!("**{}**", escape("<_*|>")), Format::Html),
+            "<b>&lt;_*|&gt;</b>"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::iter;
+
+use itertools::Itertools;
+use teloxide::adaptors::AutoSend;
+use teloxide::payloads::SetMyCommandsSetters;
+use teloxide::requests::Requester;
+use teloxide::types::{BotCommand, BotCommandScope, ChatId};
+use teloxide::utils::command::BotCommand as _;
+use teloxide::Bot;
+use tracing::warn;
+
+use crate::errors::Result;
+use crate::i18n::Lang;
+use crate::{AdminCommand, Admins, Command};
+
+// The others are personal, and only offered in private chats.
+const GROUP_COMMANDS: [&str; 4] = ["stat", "rank", "help", "about"];
+
+/// The commands offered in a kind of chat.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Menu {
+    Private,
+    Group,
+    /// Private chats with admins, also offering admin commands.
+    Admin,
+}
+
+impl Menu {
+    /// The commands of the menu, described in `lang`.
+    ///
+    /// Descriptions of the command enums are catalog keys.
+    pub fn commands(self, lang: Lang) -> Vec<BotCommand> {
+        let admin = if self == Self::Admin {
+            AdminCommand::bot_commands()
+        } else {
+            vec![]
+        };
+        Command::bot_commands()
+            .into_iter()
+            .chain(admin)
+            .filter_map(|command| {
+                let name = command.command.trim_start_matches('/');
+                (self != Self::Group || GROUP_COMMANDS.contains(&name))
+                    .then(|| BotCommand::new(name, lang.text(&command.description)))
+            })
+            .collect()
+    }
+    /// The list of commands answering `/help`.
+    pub fn help(self, lang: Lang) -> String {
+        iter::once(lang.text("help.header").to_string())
+            .chain(
+                self.commands(lang)
+                    .into_iter()
+                    .map(|command| format!("/{} - {}", command.command, command.description)),
+            )
+            .join("\n")
+    }
+}
+
+// Register the menu of a scope in every language.
+async fn register_scope(bot: &AutoSend<Bot>, menu: Menu, scope: &BotCommandScope) -> Result<()> {
+    for lang in Lang::ALL {
+        let request = bot
+            .set_my_commands(menu.commands(lang))
+            .scope(scope.clone());
+        // menus without a language are shown to users of the other languages
+        if lang == Lang::default() {
+            request.await?;
+        } else {
+            request.language_code(lang.code()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Register the menus of private chats, groups and admins' private chats in every language.
+///
+/// Fails if the menu of private chats or groups can't be registered. Those of admins are
+/// skipped with a warning, since chats with admins who never started the bot don't exist.
+pub async fn register(bot: &AutoSend<Bot>, admins: &Admins) -> Result<()> {
+    let scopes = [
+        (Menu::Private, BotCommandScope::AllPrivateChats),
+        (Menu::Group, BotCommandScope::AllGroupChats),
+    ]
+    .into_iter()
+    .chain(admins.iter().map(|admin| {
+        (
+            Menu::Admin,
+            BotCommandScope::Chat {
+                chat_id: ChatId::Id(admin),
+            },
+        )
+    }));
+    let mut failure = None;
+    for (menu, scope) in scopes {
+        match register_scope(bot, menu, &scope).await {
+            Ok(()) => {}
+            Err(e) if menu == Menu::Admin => {
+                warn!("unable to register the admin menu for {:?}: {}", scope, e);
+            }
+            Err(e) => {
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::testing::FakeApi;
+
+    #[tokio::test]
+    async fn commands_are_registered() {
+        let api = FakeApi::start(&[]);
+        register(&api.bot(), &"7".parse().unwrap()).await.unwrap();
+
+        let calls = api.calls("setMyCommands");
+        // private chats, groups and one admin, in both languages
+        assert_eq!(calls.len(), 6);
+        let menu = |scope: Value, language: Option<&str>| {
+            let call = calls
+                .iter()
+                .find(|call| call["scope"] == scope && call["language_code"].as_str() == language)
+                .expect("menu not registered");
+            call["commands"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|command| command["command"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let group = menu(json!({"type": "all_group_chats"}), None);
+        assert!(group.contains(&String::from("stat")));
+        assert!(!group.contains(&String::from("forgetme")));
+        let private = menu(json!({"type": "all_private_chats"}), Some("en"));
+        assert!(private.contains(&String::from("forgetme")));
+        assert!(!private.contains(&String::from("mute")));
+        let admin = menu(json!({"type": "chat", "chat_id": 7}), None);
+        assert!(admin.contains(&String::from("mute")));
+
+        let english = calls
+            .iter()
+            .find(|call| call["language_code"] == "en")
+            .unwrap();
+        assert_eq!(english["commands"][0]["description"], "Get started");
+    }
+
+    #[test]
+    fn help_lists_the_menu() {
+        let help = Menu::Group.help(Lang::En);
+        assert!(help.starts_with("Commands:\n"));
+        assert!(help.contains("\n/stat - Selling stats"));
+        assert!(!help.contains("/forgetme"));
+        assert!(Menu::Private
+            .help(Lang::default())
+            .contains("/forgetme - 删除我的全部数据"));
+        assert!(Menu::Admin.help(Lang::default()).contains("/mute"));
+    }
+
+    #[tokio::test]
+    async fn menus_of_unreachable_admins_are_skipped() {
+        let api = FakeApi::start(&[]);
+        api.remove_chat(7);
+        register(&api.bot(), &"7,8".parse().unwrap()).await.unwrap();
+        let calls = api.calls("setMyCommands");
+        // the admin who never started the bot fails in the first language
+        assert_eq!(calls.len(), 7);
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|call| call["scope"]["chat_id"] == 8)
+                .count(),
+            2
+        );
+    }
+}
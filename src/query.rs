@@ -0,0 +1,116 @@
+use crate::template::split_query;
+
+pub const FAVORITE_PREFIX: char = '★';
+pub const TAG_PREFIX: char = '#';
+
+/// What an inline query asks for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    /// Plain substring search, the fallback.
+    Search,
+    /// `★keyword`: the user's favorites.
+    Favorite,
+    /// `#tag`: sentences and media with the given tag.
+    Tag,
+    /// `!moan`: only moans.
+    Moan,
+    /// `?stat`: the stats card.
+    Stat,
+    /// `rank`: the leaderboard card.
+    Rank,
+    /// `random`: a pure random pick, ignoring popularity and history.
+    Random,
+}
+
+/// A parsed inline query.
+///
+/// `keyword` is the search keyword, or the tag in [`Mode::Tag`]. `args` are the
+/// template arguments after `|`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParsedQuery {
+    pub mode: Mode,
+    pub keyword: String,
+    pub args: Vec<String>,
+}
+
+impl ParsedQuery {
+    pub fn args(&self) -> Vec<&str> {
+        self.args.iter().map(String::as_str).collect()
+    }
+}
+
+/// Parse an inline query. Anything not recognized is a plain [`Mode::Search`].
+pub fn parse(query: &str) -> ParsedQuery {
+    let (head, args) = split_query(query);
+    let (mode, keyword) = match head {
+        "!moan" => (Mode::Moan, ""),
+        "?stat" => (Mode::Stat, ""),
+        "rank" => (Mode::Rank, ""),
+        "random" => (Mode::Random, ""),
+        _ => {
+            if let Some(rest) = head.strip_prefix(FAVORITE_PREFIX) {
+                (Mode::Favorite, rest.trim())
+            } else {
+                match head.strip_prefix(TAG_PREFIX).map(str::trim) {
+                    Some(tag) if !tag.is_empty() => (Mode::Tag, tag),
+                    _ => (Mode::Search, head),
+                }
+            }
+        }
+    };
+    ParsedQuery {
+        mode,
+        keyword: keyword.to_string(),
+        args: args.into_iter().map(ToString::to_string).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Mode, ParsedQuery};
+
+    fn parsed(mode: Mode, keyword: &str, args: &[&str]) -> ParsedQuery {
+        ParsedQuery {
+            mode,
+            keyword: keyword.to_string(),
+            args: args.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse("#自嘲"), parsed(Mode::Tag, "自嘲", &[]));
+        assert_eq!(parse("# 自嘲 "), parsed(Mode::Tag, "自嘲", &[]));
+        assert_eq!(parse("!moan"), parsed(Mode::Moan, "", &[]));
+        assert_eq!(parse("?stat"), parsed(Mode::Stat, "", &[]));
+        assert_eq!(parse(" rank "), parsed(Mode::Rank, "", &[]));
+        assert_eq!(parse("random"), parsed(Mode::Random, "", &[]));
+        assert_eq!(parse("★"), parsed(Mode::Favorite, "", &[]));
+        assert_eq!(parse("★ 菜"), parsed(Mode::Favorite, "菜", &[]));
+    }
+
+    #[test]
+    fn anything_else_is_a_search() {
+        assert_eq!(parse(""), parsed(Mode::Search, "", &[]));
+        assert_eq!(parse("  "), parsed(Mode::Search, "", &[]));
+        assert_eq!(parse("菜"), parsed(Mode::Search, "菜", &[]));
+        // only looking like a mode
+        assert_eq!(parse("#"), parsed(Mode::Search, "#", &[]));
+        assert_eq!(parse("!moaning"), parsed(Mode::Search, "!moaning", &[]));
+        assert_eq!(parse("stat"), parsed(Mode::Search, "stat", &[]));
+        assert_eq!(parse("rank me"), parsed(Mode::Search, "rank me", &[]));
+        assert_eq!(parse("Random"), parsed(Mode::Search, "Random", &[]));
+    }
+
+    #[test]
+    fn arguments_follow_bars() {
+        assert_eq!(
+            parse("猫 | 张三 | | 李四 "),
+            parsed(Mode::Search, "猫", &["张三", "李四"])
+        );
+        assert_eq!(parse("#自嘲|张三"), parsed(Mode::Tag, "自嘲", &["张三"]));
+        assert_eq!(parse("★|张三"), parsed(Mode::Favorite, "", &["张三"]));
+        assert_eq!(parse("!moan | 张三"), parsed(Mode::Moan, "", &["张三"]));
+        assert_eq!(parse("| 张三"), parsed(Mode::Search, "", &["张三"]));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Full buckets are dropped once this many users are tracked.
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket refilling `rate` tokens per second, holding at most `burst` tokens.
+#[derive(Debug, Copy, Clone)]
+pub struct Quota {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// Per-user token buckets.
+#[derive(Debug)]
+pub struct RateLimiter {
+    quota: Quota,
+    buckets: Mutex<HashMap<i64, Bucket>>,
+    hits: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            buckets: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+        }
+    }
+    /// Take a token from `user`'s bucket. Returns `false` and counts a hit if it's empty.
+    pub fn check(&self, user: i64) -> bool {
+        let now = Instant::now();
+        let Quota { rate, burst } = self.quota;
+        let mut buckets = self.buckets.lock();
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                (now - bucket.updated)
+                    .as_secs_f64()
+                    .mul_add(rate, bucket.tokens)
+                    < burst
+            });
+        }
+        let bucket = buckets.entry(user).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = (now - bucket.updated)
+            .as_secs_f64()
+            .mul_add(rate, bucket.tokens)
+            .min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+    /// How many requests were rejected.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+/// A slot of the global concurrency cap, released when the handler finishes.
+pub type Permit = Arc<OwnedSemaphorePermit>;
+
+/// Rate limits for inline queries and commands, sharing a global concurrency cap.
+#[derive(Debug)]
+pub struct Limits {
+    pub inline: RateLimiter,
+    pub command: RateLimiter,
+    concurrency: Arc<Semaphore>,
+    // requests rejected by the concurrency cap
+    overloaded: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(inline: Quota, command: Quota, concurrency: usize) -> Self {
+        Self {
+            inline: RateLimiter::new(inline),
+            command: RateLimiter::new(command),
+            concurrency: Arc::new(Semaphore::new(concurrency)),
+            overloaded: AtomicU64::new(0),
+        }
+    }
+    /// Admit a request of `user` through the concurrency cap and `limiter`.
+    ///
+    /// The cap comes first, so that users don't spend tokens on requests rejected by it.
+    pub fn admit(&self, limiter: &RateLimiter, user: i64) -> Option<Permit> {
+        match self.concurrency.clone().try_acquire_owned() {
+            Ok(permit) => limiter.check(user).then(|| Arc::new(permit)),
+            Err(_) => {
+                self.overloaded.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+    pub fn overloaded(&self) -> u64 {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overloaded_requests_keep_their_tokens() {
+        let quota = Quota {
+            rate: 0.0,
+            burst: 1.0,
+        };
+        let limits = Limits::new(quota, quota, 1);
+        let permit = limits.admit(&limits.inline, 1);
+        assert!(permit.is_some());
+        assert!(limits.admit(&limits.inline, 2).is_none());
+        assert_eq!(limits.overloaded(), 1);
+
+        drop(permit);
+        assert!(limits.admit(&limits.inline, 2).is_some());
+        assert!(limits.admit(&limits.inline, 2).is_none());
+        assert_eq!(limits.inline.hits(), 1);
+    }
+}
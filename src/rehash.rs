@@ -0,0 +1,93 @@
+use std::io::{BufRead, BufReader, Read};
+
+use mongodb::bson::{doc, Document};
+use mongodb::options::UpdateOptions;
+use mongodb::Database;
+
+use crate::errors::Result;
+use crate::favorites::Favorite;
+use crate::stats::User;
+use crate::utils::{keyed_mask_user, legacy_mask_user};
+
+/// Rewrites records of users from their legacy hashes to keyed ones.
+///
+/// Hashes can't be reversed, so this needs the raw user ids.
+#[derive(Debug, Clone)]
+pub struct Rehasher {
+    users: Vec<i64>,
+}
+
+impl Rehasher {
+    /// Read raw user ids, one per line.
+    pub fn from_reader(f: impl Read) -> Result<Self> {
+        let mut users = vec![];
+        for line in BufReader::new(f).lines() {
+            if let Ok(user) = line?.trim().parse() {
+                users.push(user);
+            }
+        }
+        Ok(Self { users })
+    }
+    /// Rehash users by HMAC-SHA256 keyed by `key`. Returns the number of users migrated.
+    pub async fn rehash(&self, db: Database, key: &[u8]) -> Result<usize> {
+        let coll_users = db.collection::<User>("users");
+        let coll_votes = db.collection::<Document>("votes");
+        let coll_favorites = db.collection::<Favorite>("favorites");
+        let coll_names = db.collection::<Document>("names");
+        let coll_suggestions = db.collection::<Document>("suggestions");
+
+        let mut migrated = 0;
+        for &user in &self.users {
+            let legacy = legacy_mask_user(user);
+            let keyed = keyed_mask_user(key, user);
+
+            if let Some(record) = coll_users
+                .find_one_and_delete(doc! {"user": &legacy}, None)
+                .await?
+            {
+                coll_users
+                    .update_one(
+                        doc! {"user": &keyed},
+                        doc! {"$inc": {"count": record.count as i64}},
+                        UpdateOptions::builder().upsert(true).build(),
+                    )
+                    .await?;
+                migrated += 1;
+            }
+            if let Some(favorite) = coll_favorites
+                .find_one_and_delete(doc! {"user": &legacy}, None)
+                .await?
+            {
+                coll_favorites
+                    .update_one(
+                        doc! {"user": &keyed},
+                        doc! {"$addToSet": {"sentences": {"$each": favorite.sentences}}},
+                        UpdateOptions::builder().upsert(true).build(),
+                    )
+                    .await?;
+            }
+            coll_votes
+                .update_many(
+                    doc! {"user": &legacy},
+                    doc! {"$set": {"user": &keyed}},
+                    None,
+                )
+                .await?;
+            coll_names
+                .update_one(
+                    doc! {"user": &legacy},
+                    doc! {"$set": {"user": &keyed}},
+                    None,
+                )
+                .await?;
+            coll_suggestions
+                .update_many(
+                    doc! {"submitter": &legacy},
+                    doc! {"$set": {"submitter": &keyed}},
+                    None,
+                )
+                .await?;
+        }
+        Ok(migrated)
+    }
+}
@@ -0,0 +1,311 @@
+use teloxide::types::{
+    InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle,
+    InlineQueryResultCachedGif, InlineQueryResultCachedSticker, InlineQueryResultCachedVoice,
+    InputMessageContent, InputMessageContentText,
+};
+use url::Url;
+
+use crate::corpus::{Media, MediaKind};
+use crate::i18n::Lang;
+use crate::markup::{self, Format};
+
+const MAX_TITLE_CHARS: usize = 32;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Kind {
+    Moan,
+    Sentence,
+    Recent,
+    Favorite,
+    Stat,
+    Rank,
+}
+
+impl Kind {
+    const fn thumb_name(self) -> &'static str {
+        match self {
+            Self::Moan => "moan.png",
+            Self::Sentence => "sentence.png",
+            Self::Recent => "recent.png",
+            Self::Favorite => "favorite.png",
+            Self::Stat => "stat.png",
+            Self::Rank => "rank.png",
+        }
+    }
+}
+
+fn truncate(s: &str) -> String {
+    if s.chars().count() > MAX_TITLE_CHARS {
+        let mut title: String = s.chars().take(MAX_TITLE_CHARS - 1).collect();
+        title.push('…');
+        title
+    } else {
+        s.to_string()
+    }
+}
+
+/// Buttons attached to sent sentences.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Buttons {
+    pub vote: bool,
+    pub favorite: bool,
+}
+
+impl Buttons {
+    fn keyboard(self, template: &str) -> Option<InlineKeyboardMarkup> {
+        let hash = format!("{:x}", md5::compute(template));
+        let mut row = vec![];
+        if self.vote {
+            row.push(InlineKeyboardButton::callback(
+                String::from("👍"),
+                format!("vote:up:{}", hash),
+            ));
+            row.push(InlineKeyboardButton::callback(
+                String::from("👎"),
+                format!("vote:down:{}", hash),
+            ));
+        }
+        if self.favorite {
+            row.push(InlineKeyboardButton::callback(
+                String::from("⭐"),
+                format!("fav:{}", hash),
+            ));
+        }
+        (!row.is_empty()).then(|| InlineKeyboardMarkup::new(vec![row]))
+    }
+}
+
+/// Builds inline query results.
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    format: Format,
+    thumb_base_url: Option<Url>,
+    buttons: Buttons,
+}
+
+impl Renderer {
+    pub const fn new(format: Format, thumb_base_url: Option<Url>, buttons: Buttons) -> Self {
+        Self {
+            format,
+            thumb_base_url,
+            buttons,
+        }
+    }
+}
+
+impl Renderer {
+    fn article(
+        &self,
+        kind: Kind,
+        id: String,
+        title: String,
+        content: InputMessageContentText,
+    ) -> InlineQueryResultArticle {
+        let article = InlineQueryResultArticle::new(id, title, InputMessageContent::Text(content));
+        match self
+            .thumb_base_url
+            .as_ref()
+            .and_then(|base| base.join(kind.thumb_name()).ok())
+        {
+            Some(thumb) => article.thumb_url(thumb),
+            None => article,
+        }
+    }
+    /// Shown instead of results when the user is rate limited.
+    pub fn limited(&self, id: String, lang: Lang) -> InlineQueryResult {
+        InlineQueryResult::Article(self.article(
+            Kind::Moan,
+            id,
+            lang.text("limited.title").to_string(),
+            InputMessageContentText::new(lang.text("limited.text").to_string()),
+        ))
+    }
+    pub fn moan(&self, id: String, moan: String, lang: Lang) -> InlineQueryResult {
+        InlineQueryResult::Article(self.article(
+            Kind::Moan,
+            id,
+            lang.text("moan.title").to_string(),
+            InputMessageContentText::new(moan),
+        ))
+    }
+    fn corpus_article(
+        &self,
+        kind: Kind,
+        id: String,
+        template: &str,
+        sentence: &str,
+        description: String,
+    ) -> InlineQueryResult {
+        let content = InputMessageContentText::new(markup::render(sentence, self.format))
+            .parse_mode(self.format.into());
+        let article = self
+            .article(kind, id, truncate(&markup::to_plain(sentence)), content)
+            .description(description);
+        InlineQueryResult::Article(match self.buttons.keyboard(template) {
+            Some(keyboard) => article.reply_markup(keyboard),
+            None => article,
+        })
+    }
+    /// Render a corpus sentence written in corpus markup.
+    ///
+    /// Buttons refer to `template` so that votes and favorites are counted per template.
+    pub fn sentence(
+        &self,
+        id: String,
+        template: &str,
+        sentence: &str,
+        sold: u64,
+        lang: Lang,
+    ) -> InlineQueryResult {
+        self.corpus_article(
+            Kind::Sentence,
+            id,
+            template,
+            sentence,
+            lang.format("result.sold", &[("count", &sold)]),
+        )
+    }
+    /// Render a sentence recently sent by the user.
+    pub fn recent(
+        &self,
+        id: String,
+        template: &str,
+        sentence: &str,
+        lang: Lang,
+    ) -> InlineQueryResult {
+        self.corpus_article(
+            Kind::Recent,
+            id,
+            template,
+            sentence,
+            lang.text("result.recent").to_string(),
+        )
+    }
+    /// Render a sentence starred by the user.
+    pub fn favorite(
+        &self,
+        id: String,
+        template: &str,
+        sentence: &str,
+        lang: Lang,
+    ) -> InlineQueryResult {
+        self.corpus_article(
+            Kind::Favorite,
+            id,
+            template,
+            sentence,
+            lang.text("result.favorite").to_string(),
+        )
+    }
+    pub fn stat(&self, id: String, stat: String, lang: Lang) -> InlineQueryResult {
+        InlineQueryResult::Article(self.article(
+            Kind::Stat,
+            id,
+            lang.text("stat.title").to_string(),
+            InputMessageContentText::new(stat),
+        ))
+    }
+    pub fn rank(&self, id: String, rank: String, lang: Lang) -> InlineQueryResult {
+        InlineQueryResult::Article(self.article(
+            Kind::Rank,
+            id,
+            lang.text("rank.title").to_string(),
+            InputMessageContentText::new(rank),
+        ))
+    }
+    pub fn media(&self, id: String, media: &Media, lang: Lang) -> InlineQueryResult {
+        match media.kind {
+            MediaKind::Sticker => InlineQueryResult::CachedSticker(
+                InlineQueryResultCachedSticker::new(id, &media.file_id),
+            ),
+            MediaKind::Gif => {
+                InlineQueryResult::CachedGif(InlineQueryResultCachedGif::new(id, &media.file_id))
+            }
+            MediaKind::Voice => {
+                let title = if media.tags.is_empty() {
+                    lang.text(media.kind.message_key()).to_string()
+                } else {
+                    media.tags.join(" ")
+                };
+                InlineQueryResult::CachedVoice(InlineQueryResultCachedVoice::new(
+                    id,
+                    &media.file_id,
+                    title,
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::ParseMode;
+
+    use super::*;
+    use crate::template;
+
+    // The title, text and parse mode of an article result.
+    fn article(result: InlineQueryResult) -> (String, String, Option<ParseMode>) {
+        match result {
+            InlineQueryResult::Article(article) => match article.input_message_content {
+                InputMessageContent::Text(content) => {
+                    (article.title, content.message_text, content.parse_mode)
+                }
+                content => panic!("unexpected content {:?}", content),
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    // Fill `template` with `args` the way the seller does.
+    fn sentence(renderer: &Renderer, template: &str, args: &[&str]) -> (String, String) {
+        let args: Vec<_> = args.iter().map(|arg| markup::escape(arg)).collect();
+        let args: Vec<_> = args.iter().map(String::as_str).collect();
+        let sentence = template::render(template, &args).unwrap();
+        let (title, text, _) =
+            article(renderer.sentence(String::from("id"), template, &sentence, 0, Lang::default()));
+        (title, text)
+    }
+
+    #[test]
+    fn arguments_are_escaped() {
+        let html = Renderer::new(Format::Html, None, Buttons::default());
+        assert_eq!(
+            sentence(&html, "**{name}**好菜", &["<b>&_*|"]),
+            (
+                String::from("<b>&_*|好菜"),
+                String::from("<b>&lt;b&gt;&amp;_*|</b>好菜")
+            )
+        );
+        let markdown = Renderer::new(Format::MarkdownV2, None, Buttons::default());
+        assert_eq!(
+            sentence(&markdown, "{a}__{b}__", &["<&", "_*|"]),
+            (String::from("<&_*|"), String::from(r"<&_\_\*\|_"))
+        );
+    }
+
+    #[test]
+    fn sentences_are_rendered_in_the_format() {
+        let (title, text, parse_mode) = article(
+            Renderer::new(Format::MarkdownV2, None, Buttons::default()).sentence(
+                String::from("id"),
+                "~~菜~~",
+                "~~菜~~",
+                0,
+                Lang::default(),
+            ),
+        );
+        assert_eq!(title, "菜");
+        assert_eq!(text, "~菜~");
+        assert!(matches!(parse_mode, Some(ParseMode::MarkdownV2)));
+    }
+
+    #[test]
+    fn long_titles_are_truncated() {
+        let long = "菜".repeat(MAX_TITLE_CHARS + 1);
+        let title = truncate(&long);
+        assert_eq!(title.chars().count(), MAX_TITLE_CHARS);
+        assert!(title.ends_with('…'));
+        assert_eq!(truncate("菜"), "菜");
+    }
+}
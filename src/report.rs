@@ -0,0 +1,190 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use teloxide::adaptors::AutoSend;
+use teloxide::dispatching2::UpdateHandler;
+use teloxide::dptree;
+use teloxide::dptree::di::{DependencyMap, DependencySupplier};
+use teloxide::error_handlers::ErrorHandler;
+use teloxide::payloads::AnswerCallbackQuerySetters;
+use teloxide::requests::Requester;
+use teloxide::types::{Update, UpdateKind};
+use teloxide::Bot;
+use tracing::{debug, error, warn, Instrument, Span};
+
+use crate::errors::{Error, Result};
+use crate::i18n::Lang;
+
+/// Attach the update being handled and its span to errors from `handler`, so that they can be
+/// answered.
+pub fn with_update(handler: UpdateHandler<Error>) -> UpdateHandler<Error> {
+    dptree::from_fn(move |deps: DependencyMap, cont| {
+        let handler = handler.clone();
+        async move {
+            let update: Arc<Update> = deps.get();
+            match handler.dispatch(deps).await {
+                ControlFlow::Break(Err(source)) => ControlFlow::Break(Err(Error::Handling {
+                    update: Box::new((*update).clone()),
+                    source: Box::new(source),
+                    span: Span::current(),
+                })),
+                ControlFlow::Continue(deps) => cont(deps).await,
+                done => done,
+            }
+        }
+    })
+}
+
+// Permission errors are the user's doing.
+fn is_refusal(error: &Error) -> bool {
+    matches!(error, Error::NotAdmin)
+}
+
+// The catalog key of what to tell the user about an error, if anything.
+fn friendly_message(error: &Error) -> Option<&'static str> {
+    match error {
+        // replying would fail all the same
+        Error::Telegram(_) => None,
+        Error::NotAdmin => Some("error.not_admin"),
+        Error::CorpusEmpty => Some("error.corpus_empty"),
+        Error::DB(_) | Error::Bson(_) | Error::StoreUnavailable(_) => {
+            Some("error.store_unavailable")
+        }
+        _ => Some("error.unknown"),
+    }
+}
+
+/// Answers users whose updates failed, and logs the failures so that they're alerted.
+///
+/// Refusals are only answered.
+#[derive(Debug)]
+pub struct Reporter {
+    bot: AutoSend<Bot>,
+}
+
+impl Reporter {
+    pub fn new(bot: AutoSend<Bot>) -> Arc<Self> {
+        Arc::new(Self { bot })
+    }
+    async fn reply(&self, update: &Update, text: &str) -> Result<()> {
+        match &update.kind {
+            UpdateKind::Message(msg) => {
+                self.bot.send_message(msg.chat.id, text).await?;
+            }
+            UpdateKind::CallbackQuery(query) => {
+                self.bot
+                    .answer_callback_query(&query.id)
+                    .text(text)
+                    .show_alert(true)
+                    .await?;
+            }
+            // inline queries can only be answered with results
+            _ => {}
+        }
+        Ok(())
+    }
+    async fn handle(&self, update: Option<Box<Update>>, error: Error) {
+        match (&update, is_refusal(&error)) {
+            (_, true) => debug!("refused update: {}", error),
+            (Some(update), false) => error!("error handling update {}: {}", update.id, error),
+            (None, false) => error!("error handling update: {}", error),
+        }
+
+        if let (Some(update), Some(key)) = (&update, friendly_message(&error)) {
+            let lang = update.user().map_or_else(Lang::default, Lang::of);
+            if let Err(e) = self.reply(update, lang.text(key)).await {
+                warn!("unable to answer a failed update: {:?}", e);
+            }
+        }
+    }
+}
+
+impl ErrorHandler<Error> for Reporter {
+    fn handle_error(self: Arc<Self>, error: Error) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            match error {
+                Error::Handling {
+                    update,
+                    source,
+                    span,
+                } => self.handle(Some(update), *source).instrument(span).await,
+                error => self.handle(None, error).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use teloxide::types::MessageKind;
+    use teloxide::RequestError;
+
+    use super::*;
+    use crate::testing::FakeApi;
+
+    const USER: i64 = 42;
+
+    #[tokio::test]
+    async fn failures_are_answered() {
+        let api = FakeApi::start(&[]);
+        let reporter = Reporter::new(api.bot());
+        // `Update` fails to deserialize from a `Value`
+        let update: Update = serde_json::from_str(
+            &json!({
+                "update_id": 1,
+                "message": {
+                    "message_id": 1,
+                    "date": 0,
+                    "chat": {"id": USER, "type": "private", "first_name": "user"},
+                    "from": {"id": USER, "is_bot": false, "first_name": "user"},
+                    "text": "/stat"
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let failure = |source| Error::Handling {
+            update: Box::new(update.clone()),
+            source: Box::new(source),
+            span: Span::none(),
+        };
+
+        reporter
+            .clone()
+            .handle_error(failure(Error::StoreUnavailable("total")))
+            .await;
+        let calls = api.calls("sendMessage");
+        assert_eq!(calls[0]["chat_id"], USER);
+        assert_eq!(calls[0]["text"], "数据库暂时不可用，请稍后再试");
+
+        // failed replies aren't retried
+        reporter
+            .clone()
+            .handle_error(failure(Error::Telegram(RequestError::RetryAfter(5))))
+            .await;
+        assert_eq!(api.calls("sendMessage").len(), 1);
+
+        // users are answered in the language of their clients
+        let mut update = update.clone();
+        if let UpdateKind::Message(msg) = &mut update.kind {
+            if let MessageKind::Common(common) = &mut msg.kind {
+                if let Some(user) = &mut common.from {
+                    user.language_code = Some(String::from("en-US"));
+                }
+            }
+        }
+        reporter
+            .handle_error(Error::Handling {
+                update: Box::new(update),
+                source: Box::new(Error::CorpusEmpty),
+                span: Span::none(),
+            })
+            .await;
+        assert_eq!(
+            api.calls("sendMessage")[1]["text"],
+            "The corpus is empty for now, please try again later"
+        );
+    }
+}
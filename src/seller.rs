@@ -1,11 +1,19 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use either::Either;
+use itertools::Itertools;
+use parking_lot::{RwLock, RwLockReadGuard};
 use rand::seq::IteratorRandom;
 use rand::seq::SliceRandom;
-use rand::{random, thread_rng};
+use rand::Rng;
+use serde::Deserialize;
+use tracing::warn;
 
-use crate::corpus::CorpusClient;
+use crate::catalog::MediaCatalog;
+use crate::corpus::{CorpusClient, Entry, Media};
+use crate::stats::Stat;
+use crate::suggestion::SuggestionQueue;
+use crate::{markup, template};
 
 #[rustfmt::skip]
 const SEP: [&str; 16] = [
@@ -31,61 +39,483 @@ const MOAN: [&str; 35] = [
     "不要", "那里不可以", "好变态", "要坏掉啦",
 ];
 
-fn random_sep() -> &'static str {
-    let mut rng = thread_rng();
-    SEP.choose(&mut rng).unwrap()
+/// Vocabulary of generated moans: separators and interjections, picked uniformly,
+/// so that repeating an item makes it likelier.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MoanGrammar {
+    pub seps: Vec<String>,
+    pub moans: Vec<String>,
 }
 
-fn random_moan() -> &'static str {
-    let mut rng = thread_rng();
-    MOAN.choose(&mut rng).unwrap()
+impl Default for MoanGrammar {
+    fn default() -> Self {
+        Self {
+            seps: SEP.iter().map(ToString::to_string).collect(),
+            moans: MOAN.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl MoanGrammar {
+    fn random_sep(&self, rng: &mut impl Rng) -> &str {
+        self.seps.choose(rng).map_or("", String::as_str)
+    }
+    fn random_moan(&self, rng: &mut impl Rng) -> &str {
+        self.moans.choose(rng).map_or("", String::as_str)
+    }
+    fn random_text(&self, rng: &mut impl Rng) -> String {
+        let mut text = self.random_moan(rng).to_string();
+        while text.chars().count() < 20 && rng.gen::<f32>() < 0.25 {
+            text.push_str(self.random_sep(rng));
+            text.push_str(self.random_moan(rng));
+        }
+        text
+    }
+}
+
+// Whether `template` can be rendered with `args`. Templates are only filled when arguments
+// are given, and plain sentences only picked when there are none.
+fn fits(template: &str, args: &[&str]) -> bool {
+    let slots = template::slots(template).len();
+    args.is_empty() == (slots == 0) && slots <= args.len()
 }
 
-fn random_text() -> String {
-    let mut text = random_moan().to_string();
-    while text.chars().count() < 20 && random::<f32>() < 0.25 {
-        text.push_str(random_sep());
-        text.push_str(random_moan());
+// Render `template` with `args`, if it fits them.
+fn fill(template: &str, args: &[&str]) -> Option<(String, String)> {
+    if !fits(template, args) {
+        return None;
     }
-    text
+    template::render(template, args).map(|rendered| (template.to_string(), rendered))
 }
 
-#[derive(Debug, Clone)]
+// Escape arguments so that they don't interfere with corpus markup.
+fn escape_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| markup::escape(arg)).collect()
+}
+
+fn hash(text: &str) -> String {
+    format!("{:x}", md5::compute(text))
+}
+
+// The merged sentences, rebuilt whenever the corpus or the approved suggestions change.
+#[derive(Debug, Default)]
+struct Index {
+    // corpus version and number of approved suggestions it was built from
+    source: Option<(String, usize)>,
+    // approved suggestions not among the common sentences
+    extra: Vec<Entry>,
+    // md5 hash of every sentence to the sentence
+    hashes: HashMap<String, String>,
+}
+
+impl Index {
+    fn new(source: (String, usize), common: &[Entry], overlay: &[Entry]) -> Self {
+        let texts: HashSet<&str> = common.iter().map(|entry| entry.text.as_str()).collect();
+        let extra = overlay
+            .iter()
+            .filter(|entry| !texts.contains(entry.text.as_str()))
+            .cloned()
+            .collect_vec();
+        let hashes = common
+            .iter()
+            .chain(&extra)
+            .map(|entry| (hash(&entry.text), entry.text.clone()))
+            .collect();
+        Self {
+            source: Some(source),
+            extra,
+            hashes,
+        }
+    }
+}
+
+/// Which sentences to sell.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Order<'a> {
+    /// Substring of the sentence, or one of its tags.
+    pub keyword: &'a str,
+    /// Only sell sentences with this tag.
+    pub tag: Option<&'a str>,
+    pub args: &'a [&'a str],
+    /// Whether NSFW sentences may be sold.
+    pub nsfw: bool,
+}
+
+impl Order<'_> {
+    fn accepts(&self, entry: &Entry) -> bool {
+        entry.weight > 0.0
+            && (self.nsfw || !entry.nsfw)
+            && self
+                .tag
+                .is_none_or(|tag| entry.tags.iter().any(|t| t == tag))
+            && (entry.text.contains(self.keyword) || entry.tags.iter().any(|t| t == self.keyword))
+    }
+}
+
+/// How sentences are picked from the candidates.
+#[derive(Debug, Copy, Clone)]
+pub enum Selection {
+    Uniform,
+    /// Weighted by popularity. `exploration` in `[0, 1]` is the weight share given
+    /// uniformly to every sentence, so that rarely sold ones still appear.
+    Weighted {
+        exploration: f64,
+    },
+}
+
+impl Selection {
+    /// Weighted selection, if `exploration` is in `[0, 1]`.
+    pub fn weighted(exploration: f64) -> Option<Self> {
+        (0.0..=1.0)
+            .contains(&exploration)
+            .then_some(Self::Weighted { exploration })
+    }
+    // The weight of a sentence of corpus weight `weight`, given the top popularity `max`
+    // among the candidates. Without any popular candidate, the corpus weight is kept.
+    fn weight(self, weight: f64, popularity: f64, max: f64) -> f64 {
+        match self {
+            Self::Weighted { exploration } if max > 0.0 => {
+                weight * (1.0 - exploration).mul_add(popularity / max, exploration)
+            }
+            _ => weight,
+        }
+    }
+    // Pick at most `amount` of the `(template, weight)` candidates.
+    fn pick<'a>(
+        self,
+        candidates: &[(&'a str, f64)],
+        stats: &Stat,
+        amount: usize,
+        rng: &mut impl Rng,
+    ) -> Vec<&'a str> {
+        let max = match self {
+            Self::Uniform => 0.0,
+            Self::Weighted { .. } => candidates
+                .iter()
+                .map(|(template, _)| stats.popularity(template))
+                .fold(0.0, f64::max),
+        };
+        candidates
+            .choose_multiple_weighted(rng, amount, |(template, weight)| {
+                self.weight(*weight, stats.popularity(template), max)
+            })
+            .map(|chosen| chosen.map(|(template, _)| *template).collect())
+            .unwrap_or_else(|e| {
+                warn!("unable to pick sentences: {}", e);
+                vec![]
+            })
+    }
+}
+
+#[derive(Debug)]
 pub struct Seller {
     client: Arc<CorpusClient>,
+    catalog: Arc<MediaCatalog>,
+    suggestions: Arc<SuggestionQueue>,
+    selection: Selection,
+    grammar: MoanGrammar,
+    index: RwLock<Index>,
 }
 
 impl Seller {
-    pub fn new(client: Arc<CorpusClient>) -> Self {
-        Self { client }
+    pub fn new(
+        client: Arc<CorpusClient>,
+        catalog: Arc<MediaCatalog>,
+        suggestions: Arc<SuggestionQueue>,
+        selection: Selection,
+        grammar: MoanGrammar,
+    ) -> Self {
+        Self {
+            client,
+            catalog,
+            suggestions,
+            selection,
+            grammar,
+            index: RwLock::new(Index::default()),
+        }
     }
 }
 
+// The sentences accepted by `order` and fitting `args`, along with their corpus weight.
+fn candidates<'a>(
+    common: &'a [Entry],
+    extra: &'a [Entry],
+    order: &Order,
+    args: &[&str],
+) -> Vec<(&'a str, f64)> {
+    common
+        .iter()
+        .chain(extra)
+        .filter(|entry| order.accepts(entry) && fits(&entry.text, args))
+        .map(|entry| (entry.text.as_str(), entry.weight))
+        .collect_vec()
+}
+
 impl Seller {
-    pub fn sell(&self, keyword: &str) -> Vec<String> {
+    // The merged sentences, rebuilt first if outdated. No other lock is held meanwhile,
+    // so that the index may be locked before the corpus.
+    fn index(&self) -> RwLockReadGuard<Index> {
+        let built = self.index.read().source.clone();
+        let rebuilt = {
+            let corpus = self.client.corpus();
+            let overlay = self.suggestions.approved();
+            let source = (corpus.version.clone(), overlay.len());
+            (built.as_ref() != Some(&source)).then(|| Index::new(source, &corpus.common, &overlay))
+        };
+        if let Some(index) = rebuilt {
+            *self.index.write() = index;
+        }
+        self.index.read()
+    }
+    /// Pick sentences accepted by `order` and render them with its arguments.
+    ///
+    /// Returns `(template, rendered)` pairs. Templates are only picked when
+    /// arguments are given and all of their placeholders can be filled.
+    /// Approved suggestions are merged into the common corpus.
+    /// Sentences in `recent` are skipped unless nothing else matches.
+    pub fn sell(
+        &self,
+        order: &Order,
+        stats: &Stat,
+        recent: &[String],
+        rng: &mut impl Rng,
+    ) -> Vec<(String, String)> {
+        let args = escape_args(order.args);
+        let args = args.iter().map(String::as_str).collect_vec();
+        let index = self.index();
         let corpus = self.client.corpus();
-        let mut rng = thread_rng();
-        if keyword.is_empty() {
-            Either::Left(corpus.common.iter())
+        let candidates = candidates(&corpus.common, &index.extra, order, &args);
+        let candidates = if candidates
+            .iter()
+            .all(|(template, _)| recent.iter().any(|s| s == template))
+        {
+            candidates
         } else {
-            Either::Right(corpus.common.iter().filter(|s| s.contains(keyword)))
+            candidates
+                .into_iter()
+                .filter(|(template, _)| !recent.iter().any(|s| s == template))
+                .collect_vec()
+        };
+        self.selection
+            .pick(&candidates, stats, 5, rng)
+            .into_iter()
+            .filter_map(|template| fill(template, &args))
+            .collect()
+    }
+    /// Pick sentences uniformly at random, ignoring popularity, history and corpus weights.
+    pub fn sell_random(&self, order: &Order, rng: &mut impl Rng) -> Vec<(String, String)> {
+        let args = escape_args(order.args);
+        let args = args.iter().map(String::as_str).collect_vec();
+        let index = self.index();
+        let corpus = self.client.corpus();
+        candidates(&corpus.common, &index.extra, order, &args)
+            .into_iter()
+            .choose_multiple(rng, 5)
+            .into_iter()
+            .filter_map(|(template, _)| fill(template, &args))
+            .collect()
+    }
+    /// Sell counts summed by tag, most sold first.
+    pub fn tag_breakdown(&self, stats: &Stat) -> Vec<(String, u64)> {
+        let index = self.index();
+        let corpus = self.client.corpus();
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        for entry in corpus.common.iter().chain(&index.extra) {
+            let count = stats.sentences.get(&entry.text).copied().unwrap_or(0);
+            for tag in &entry.tags {
+                *counts.entry(tag).or_default() += count;
+            }
         }
-        .cloned()
-        .choose_multiple(&mut rng, 5)
+        counts
+            .into_iter()
+            .sorted_by_key(|(_, count)| -(*count as i128))
+            .take(5)
+            .map(|(tag, count)| (tag.to_string(), count))
+            .collect()
+    }
+    /// Render at most `limit` of the given sentences (e.g. the user's recent ones or favorites)
+    /// that contain the keyword of `order` and fit its arguments, keeping their order.
+    ///
+    /// NSFW sentences are skipped unless `order` allows them.
+    pub fn resell(
+        &self,
+        order: &Order,
+        sentences: &[String],
+        limit: usize,
+    ) -> Vec<(String, String)> {
+        let args = escape_args(order.args);
+        let args = args.iter().map(String::as_str).collect_vec();
+        let corpus = self.client.corpus();
+        let overlay = self.suggestions.approved();
+        sentences
+            .iter()
+            .filter(|s| s.contains(order.keyword))
+            .filter(|s| {
+                order.nsfw
+                    || !corpus
+                        .common
+                        .iter()
+                        .chain(overlay.iter())
+                        .any(|entry| entry.nsfw && &entry.text == *s)
+            })
+            .filter_map(|s| fill(s, &args))
+            .take(limit)
+            .collect()
+    }
+    /// Whether `sentence` is in the corpus or the approved suggestions.
+    pub fn contains(&self, sentence: &str) -> bool {
+        self.index()
+            .hashes
+            .get(&hash(sentence))
+            .is_some_and(|text| text == sentence)
+    }
+    /// Find the sentence whose md5 hash is `hash`.
+    pub fn lookup(&self, hash: &str) -> Option<String> {
+        self.index().hashes.get(hash).cloned()
     }
-    pub fn moan(&self) -> String {
+    // Pick media from both the corpus and the local catalog having a tag satisfying `pred`.
+    fn pick_media(&self, pred: impl Fn(&str) -> bool, rng: &mut impl Rng) -> Vec<Media> {
         let corpus = self.client.corpus();
-        let mut rng = thread_rng();
-        let count = (1..=3).choose(&mut rng).unwrap();
-        let phrase_set = corpus.phrase.iter().choose(&mut rng).unwrap();
-        let vegetables = phrase_set.choose_multiple(&mut rng, count);
+        let catalog = self.catalog.entries();
+        corpus
+            .media
+            .iter()
+            .chain(catalog.iter().map(|entry| &entry.media))
+            .filter(|media| media.tags.iter().any(|tag| pred(tag)))
+            .cloned()
+            .choose_multiple(rng, 5)
+    }
+    /// Pick media with a tag containing `keyword`.
+    pub fn sell_media(&self, keyword: &str, rng: &mut impl Rng) -> Vec<Media> {
+        if keyword.is_empty() {
+            return vec![];
+        }
+        self.pick_media(|tag| tag.contains(keyword), rng)
+    }
+    /// Pick media tagged exactly with `tag`.
+    pub fn sell_tagged_media(&self, tag: &str, rng: &mut impl Rng) -> Vec<Media> {
+        self.pick_media(|t| t == tag, rng)
+    }
+    pub fn moan(&self, rng: &mut impl Rng) -> String {
+        let corpus = self.client.corpus();
+        let count = (1..=3).choose(rng).unwrap();
+        let phrase_set = corpus.phrase.iter().choose(rng).unwrap();
+        let vegetables = phrase_set.choose_multiple(rng, count).collect_vec();
 
         vegetables.into_iter().fold(String::new(), |mut x, acc| {
             x.push_str(acc);
-            x.push_str(random_sep());
-            x.push_str(random_text().as_str());
-            x.push_str(random_sep());
+            x.push_str(self.grammar.random_sep(rng));
+            x.push_str(self.grammar.random_text(rng).as_str());
+            x.push_str(self.grammar.random_sep(rng));
             x
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn stats(sentences: &[(&str, u64)]) -> Stat {
+        Stat {
+            total: sentences.iter().map(|(_, count)| count).sum(),
+            sentences: sentences
+                .iter()
+                .map(|(sentence, count)| ((*sentence).to_string(), *count))
+                .collect(),
+            users: HashMap::new(),
+            media: HashMap::new(),
+            scores: HashMap::new(),
+        }
+    }
+
+    fn candidates<'a>(templates: &[&'a str]) -> Vec<(&'a str, f64)> {
+        templates.iter().map(|template| (*template, 1.0)).collect()
+    }
+
+    #[test]
+    fn exploration_is_bounded() {
+        assert!(Selection::weighted(0.0).is_some());
+        assert!(Selection::weighted(1.0).is_some());
+        assert!(Selection::weighted(-0.1).is_none());
+        assert!(Selection::weighted(1.1).is_none());
+        assert!(Selection::weighted(f64::NAN).is_none());
+    }
+
+    #[test]
+    fn popularity_is_blended_with_exploration() {
+        let weighted = Selection::Weighted { exploration: 0.2 };
+        // the most popular keeps its corpus weight, and the least gets the exploration share
+        assert!((weighted.weight(2.0, 10.0, 10.0) - 2.0).abs() < 1e-9);
+        assert!((weighted.weight(2.0, 5.0, 10.0) - 1.2).abs() < 1e-9);
+        assert!((weighted.weight(2.0, 0.0, 10.0) - 0.4).abs() < 1e-9);
+        // nothing is popular yet
+        assert!((weighted.weight(2.0, 0.0, 0.0) - 2.0).abs() < 1e-9);
+        let exploring = Selection::Weighted { exploration: 1.0 };
+        assert!((exploring.weight(2.0, 0.0, 10.0) - 2.0).abs() < 1e-9);
+        assert!((Selection::Uniform.weight(2.0, 0.0, 10.0) - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unpopular_sentences_are_left_without_exploration() {
+        let stats = stats(&[("热门", 10)]);
+        let candidates = candidates(&["热门", "冷门", "无人问津"]);
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let picked =
+                Selection::Weighted { exploration: 0.0 }.pick(&candidates, &stats, 1, &mut rng);
+            assert_eq!(picked[0], "热门");
+        }
+    }
+
+    #[test]
+    fn sentences_are_picked_by_corpus_weight_without_popularity() {
+        let stats = stats(&[]);
+        let mut candidates = candidates(&["甲", "乙"]);
+        candidates[1].1 = 0.0;
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let picked =
+                Selection::Weighted { exploration: 0.2 }.pick(&candidates, &stats, 1, &mut rng);
+            assert_eq!(picked[0], "甲");
+        }
+        // invalid weights are logged, and nothing is picked
+        candidates[1].1 = -1.0;
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(Selection::Uniform
+            .pick(&candidates, &stats, 2, &mut rng)
+            .is_empty());
+    }
+
+    #[test]
+    fn overlay_is_merged_once() {
+        let common = vec![
+            Entry::plain("甲".to_string()),
+            Entry::plain("乙".to_string()),
+        ];
+        let overlay = vec![
+            Entry::plain("乙".to_string()),
+            Entry::plain("丙".to_string()),
+        ];
+        let index = Index::new((String::new(), overlay.len()), &common, &overlay);
+        assert_eq!(
+            index
+                .extra
+                .iter()
+                .map(|entry| entry.text.as_str())
+                .collect_vec(),
+            ["丙"]
+        );
+        assert_eq!(index.hashes.len(), 3);
+        assert_eq!(
+            index.hashes.get(&hash("丙")).map(String::as_str),
+            Some("丙")
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use teloxide::adaptors::AutoSend;
+use teloxide::dispatching::update_listeners::UpdateListener;
+use teloxide::dispatching::ShutdownToken;
+use teloxide::dispatching2::Dispatcher;
+use teloxide::error_handlers::LoggingErrorHandler;
+use teloxide::Bot;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::errors::Error;
+
+/// Coordinates shutdown of the dispatchers and background tasks on SIGINT.
+///
+/// Dispatchers stop taking updates and finish the ones being handled, while background
+/// loops finish their current run. Stats writes of handlers dropped after the drain are
+/// flushed by the loggers meanwhile. Everything must be done within fly's `kill_timeout`.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shutdown {
+    /// Cancelled once shutdown begins.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+    /// Wait for `task` before exiting.
+    pub fn track(&self, task: JoinHandle<()>) {
+        self.tasks.lock().push(task);
+    }
+    /// Shut the dispatchers and background loops down on SIGINT.
+    pub fn listen(&self, dispatchers: Vec<ShutdownToken>) {
+        let token = self.token.clone();
+        tokio::spawn(async move {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("unable to listen for SIGINT");
+            info!("SIGINT received, shutting down");
+            token.cancel();
+            for dispatcher in dispatchers {
+                // not dispatching yet, or already shut down
+                if dispatcher.shutdown().is_err() {
+                    warn!("dispatcher isn't running");
+                }
+            }
+        });
+    }
+    /// Stop background loops, e.g. when the dispatcher stopped by itself.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+    /// Dispatch updates from `listener` until shutdown, giving the in-flight handler `drain`
+    /// to finish after shutdown begins.
+    pub async fn dispatch<E: Debug>(
+        &self,
+        dispatcher: &mut Dispatcher<AutoSend<Bot>, Error>,
+        listener: impl UpdateListener<E>,
+        drain: Duration,
+    ) {
+        let deadline = async {
+            self.token.cancelled().await;
+            tokio::time::sleep(drain).await;
+        };
+        tokio::select! {
+            _ = dispatcher.dispatch_with_listener(listener, LoggingErrorHandler::new()) => {}
+            _ = deadline => warn!("handlers didn't finish in time"),
+        }
+    }
+    /// Wait for background loops to finish their current run, at most for `timeout`.
+    pub async fn join(&self, timeout: Duration) {
+        let tasks = std::mem::take(&mut *self.tasks.lock());
+        let joined = futures_util::future::join_all(tasks);
+        if tokio::time::timeout(timeout, joined).await.is_err() {
+            warn!("background tasks didn't finish in time");
+        }
+    }
+}
@@ -1,14 +1,24 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures_util::{StreamExt, TryStreamExt};
 use itertools::Itertools;
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
 use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use mongodb::{Collection, Database};
 use parking_lot::{RwLock, RwLockReadGuard};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::errors::Result;
+use crate::corpus::MediaKind;
+use crate::errors::{Error, Result};
+use crate::utils::{Masker, LEGACY_HASH_LEN};
+
+/// Logged in place of a sentence when a moan or an unknown result is chosen.
+pub const MOAN_SENTENCE: &str = "-1";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Total {
@@ -27,35 +37,62 @@ pub struct Sentence {
     pub count: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Score {
+    pub sentence: String,
+    pub score: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Vote {
+    pub sentence: String,
+    pub user: String,
+    pub vote: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MediaRecord {
+    pub kind: MediaKind,
+    pub file_id: String,
+    pub count: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct Summary {
     pub total: u64,
     pub users: u64,
     pub top_sentences: Vec<(String, u64)>,
     pub top_user_count: u64,
+    pub media: Vec<(MediaKind, u64)>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Stat {
     pub total: u64,
     pub sentences: HashMap<String, u64>,
     pub users: HashMap<String, u64>,
+    pub media: HashMap<(MediaKind, String), u64>,
+    pub scores: HashMap<String, i64>,
 }
 
+// How many sells a single vote is worth when weighting sentences.
+const VOTE_WEIGHT: f64 = 5.0;
+
 impl Stat {
+    /// Popularity of a sentence from its sell count and vote score.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn popularity(&self, sentence: &str) -> f64 {
+        let count = self.sentences.get(sentence).copied().unwrap_or(0) as f64;
+        let score = self.scores.get(sentence).copied().unwrap_or(0) as f64;
+        VOTE_WEIGHT.mul_add(score, count).max(0.0)
+    }
     pub fn summary(&self) -> Summary {
         let top_sentences = self
             .sentences
             .iter()
             .sorted_by_key(|item| -(*item.1 as i128))
             .take(5)
-            .map(|item| {
-                if item.0 == "-1" {
-                    (String::from("菜喘"), *item.1)
-                } else {
-                    (item.0.clone(), *item.1)
-                }
-            })
+            .map(|item| (item.0.clone(), *item.1))
             .collect();
         let top_user_count = self
             .users
@@ -65,11 +102,24 @@ impl Stat {
             .map(|item| *item.1)
             .find_or_first(|_| true)
             .unwrap_or(0);
+        let media = MediaKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let count = self
+                    .media
+                    .iter()
+                    .filter(|((k, _), _)| *k == kind)
+                    .map(|(_, count)| count)
+                    .sum();
+                (kind, count)
+            })
+            .collect();
         Summary {
             total: self.total,
             users: self.users.len() as u64,
             top_sentences,
             top_user_count,
+            media,
         }
     }
 }
@@ -79,13 +129,38 @@ pub struct MongoDBLogger {
     coll_total: Collection<Total>,
     coll_sentences: Collection<Sentence>,
     coll_users: Collection<User>,
+    coll_media: Collection<MediaRecord>,
+    coll_scores: Collection<Score>,
+    coll_votes: Collection<Vote>,
     stats: RwLock<Stat>,
+    masker: Masker,
+    // held for reading by each write in flight, so that `flush` can wait for them
+    writes: Arc<tokio::sync::RwLock<()>>,
+}
+
+// Increment the counter of the document matching `filter`, creating it if missing.
+async fn increment<T>(coll: &Collection<T>, filter: Document, field: &str) -> Result<Option<T>>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    Ok(coll
+        .find_one_and_update(
+            filter,
+            doc! {"$inc": {field: 1}},
+            FindOneAndUpdateOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?)
 }
 
 async fn fetch_stats(
     total: &Collection<Total>,
     sentences: &Collection<Sentence>,
     users: &Collection<User>,
+    media: &Collection<MediaRecord>,
+    scores: &Collection<Score>,
 ) -> Result<Stat> {
     let total = total
         .find_one_and_update(
@@ -97,7 +172,7 @@ async fn fetch_stats(
                 .build(),
         )
         .await?
-        .unwrap();
+        .ok_or(Error::StoreUnavailable("total"))?;
     let sentences: HashMap<_, _> = sentences
         .find(doc! {"sentence": {"$exists": true}}, None)
         .await?
@@ -110,29 +185,116 @@ async fn fetch_stats(
         .map(|item| item.map(|user| (user.user, user.count)))
         .try_collect()
         .await?;
+    let media: HashMap<_, _> = media
+        .find(doc! {"file_id": {"$exists": true}}, None)
+        .await?
+        .map(|item| item.map(|media| ((media.kind, media.file_id), media.count)))
+        .try_collect()
+        .await?;
+    let scores: HashMap<_, _> = scores
+        .find(doc! {"sentence": {"$exists": true}}, None)
+        .await?
+        .map(|item| item.map(|score| (score.sentence, score.score)))
+        .try_collect()
+        .await?;
     Ok(Stat {
         total: total.total,
         sentences,
         users,
+        media,
+        scores,
     })
 }
 
 impl MongoDBLogger {
-    pub async fn new(db: Database) -> Result<Self> {
+    /// Load the stats of `db`, masking users by HMAC-SHA256 keyed by `mask_key` if any.
+    ///
+    /// Users already recorded under their legacy hash keep it until they're migrated.
+    pub async fn new(db: Database, mask_key: Option<Vec<u8>>) -> Result<Self> {
         let coll_total = db.collection("stats");
         let coll_sentences = db.collection("sentences");
         let coll_users = db.collection("users");
-        let stats = fetch_stats(&coll_total, &coll_sentences, &coll_users).await?;
+        let coll_media = db.collection("media");
+        let coll_scores = db.collection("scores");
+        let coll_votes = db.collection("votes");
+        let stats = fetch_stats(
+            &coll_total,
+            &coll_sentences,
+            &coll_users,
+            &coll_media,
+            &coll_scores,
+        )
+        .await?;
+        let legacy = stats
+            .users
+            .keys()
+            .filter(|user| user.len() == LEGACY_HASH_LEN)
+            .cloned()
+            .collect::<Vec<_>>();
         Ok(Self {
             coll_total,
             coll_sentences,
             coll_users,
+            coll_media,
+            coll_scores,
+            coll_votes,
             stats: RwLock::new(stats),
+            masker: Masker::new(mask_key, legacy),
+            writes: Arc::default(),
         })
     }
+    /// No stats, without reading `db`.
+    #[cfg(test)]
+    pub fn empty(db: &Database) -> Self {
+        Self {
+            coll_total: db.collection("stats"),
+            coll_sentences: db.collection("sentences"),
+            coll_users: db.collection("users"),
+            coll_media: db.collection("media"),
+            coll_scores: db.collection("scores"),
+            coll_votes: db.collection("votes"),
+            stats: RwLock::default(),
+            masker: Masker::new(None, []),
+            writes: Arc::default(),
+        }
+    }
+    // Run `write` in a task of its own, so that it's finished even if the handler awaiting it
+    // is dropped on shutdown.
+    async fn spawn_write<T: Send + 'static>(
+        &self,
+        write: impl Future<Output = Result<T>> + Send + 'static,
+    ) -> Result<T> {
+        let guard = self.writes.clone().read_owned().await;
+        tokio::spawn(async move {
+            let result = write.await;
+            drop(guard);
+            result
+        })
+        .await
+        .expect("stats write panicked")
+    }
+    /// Wait for the writes in flight to finish, at most for `timeout`.
+    pub async fn flush(&self, timeout: Duration) {
+        if tokio::time::timeout(timeout, self.writes.write())
+            .await
+            .is_err()
+        {
+            warn!("stats writes didn't finish in time");
+        }
+    }
+    /// The hash the records of `user` are stored under.
+    pub fn mask_user(&self, user: i64) -> String {
+        self.masker.mask(user)
+    }
     pub async fn sync(&self) -> Result<()> {
-        let new_stats =
-            fetch_stats(&self.coll_total, &self.coll_sentences, &self.coll_users).await?;
+        let new_stats = fetch_stats(
+            &self.coll_total,
+            &self.coll_sentences,
+            &self.coll_users,
+            &self.coll_media,
+            &self.coll_scores,
+        )
+        .await?;
 
         let mut stats = self.stats.write();
         *stats = new_stats;
@@ -143,55 +305,176 @@ impl MongoDBLogger {
         self.stats.read()
     }
     pub async fn log(&self, sentence: String, user: String) -> Result<()> {
-        let config = FindOneAndUpdateOptions::builder()
-            .upsert(true)
-            .return_document(ReturnDocument::After)
-            .build();
-        let total = self
-            .coll_total
+        let coll_total = self.coll_total.clone();
+        let coll_sentences = self.coll_sentences.clone();
+        let coll_users = self.coll_users.clone();
+        let (total, sentence, user) = self
+            .spawn_write(async move {
+                let total = increment(&coll_total, doc! {"total": {"$exists": true}}, "total")
+                    .await?
+                    .ok_or(Error::StoreUnavailable("total"))?;
+                let sentence = increment(&coll_sentences, doc! {"sentence": sentence}, "count")
+                    .await?
+                    .ok_or(Error::StoreUnavailable("sentence"))?;
+                let user = increment(&coll_users, doc! {"user": user}, "count")
+                    .await?
+                    .ok_or(Error::StoreUnavailable("user"))?;
+                Ok((total, sentence, user))
+            })
+            .await?;
+
+        let mut stats = self.stats.write();
+        stats.total = total.total;
+        stats.sentences.insert(sentence.sentence, sentence.count);
+        stats.users.insert(user.user, user.count);
+
+        Ok(())
+    }
+    pub async fn log_media(&self, kind: MediaKind, file_id: String, user: String) -> Result<()> {
+        let coll_total = self.coll_total.clone();
+        let coll_media = self.coll_media.clone();
+        let coll_users = self.coll_users.clone();
+        let (total, media, user) = self
+            .spawn_write(async move {
+                let total = increment(&coll_total, doc! {"total": {"$exists": true}}, "total")
+                    .await?
+                    .ok_or(Error::StoreUnavailable("total"))?;
+                let filter = doc! {
+                    "kind": mongodb::bson::to_bson(&kind)?,
+                    "file_id": file_id
+                };
+                let media = increment(&coll_media, filter, "count")
+                    .await?
+                    .ok_or(Error::StoreUnavailable("media record"))?;
+                let user = increment(&coll_users, doc! {"user": user}, "count")
+                    .await?
+                    .ok_or(Error::StoreUnavailable("user"))?;
+                Ok((total, media, user))
+            })
+            .await?;
+
+        let mut stats = self.stats.write();
+        stats.total = total.total;
+        stats.media.insert((media.kind, media.file_id), media.count);
+        stats.users.insert(user.user, user.count);
+
+        Ok(())
+    }
+    /// Record a user's vote (`1` or `-1`) on a sentence, replacing their previous vote.
+    pub async fn vote(&self, sentence: String, user: String, vote: i64) -> Result<()> {
+        let previous = self
+            .coll_votes
             .find_one_and_update(
                 doc! {
-                    "total": {"$exists": true}
+                    "sentence": &sentence,
+                    "user": user
                 },
                 doc! {
-                    "$inc": {"total": 1}
+                    "$set": {"vote": vote}
                 },
-                config.clone(),
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::Before)
+                    .build(),
             )
-            .await?
-            .unwrap();
-        let sentence = self
-            .coll_sentences
+            .await?;
+        let delta = vote - previous.map_or(0, |previous| previous.vote);
+        if delta == 0 {
+            return Ok(());
+        }
+
+        let score = self
+            .coll_scores
             .find_one_and_update(
                 doc! {
                     "sentence": sentence
                 },
                 doc! {
-                    "$inc": {"count": 1}
+                    "$inc": {"score": delta}
                 },
-                config.clone(),
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
             )
             .await?
-            .unwrap();
-        let user = self
-            .coll_users
-            .find_one_and_update(
-                doc! {
-                    "user": user
-                },
-                doc! {
-                    "$inc": {"count": 1}
-                },
-                config,
-            )
+            .ok_or(Error::StoreUnavailable("score"))?;
+
+        self.stats
+            .write()
+            .scores
+            .insert(score.sentence, score.score);
+
+        Ok(())
+    }
+    /// Remove a user's record and withdraw their votes.
+    ///
+    /// Sentence and media counts have no per-user breakdown, so they are kept, and so is
+    /// the total.
+    pub async fn forget(&self, user: &str) -> Result<()> {
+        let votes: Vec<Vote> = self
+            .coll_votes
+            .find(doc! {"user": user}, None)
             .await?
-            .unwrap();
+            .try_collect()
+            .await?;
+        for vote in votes {
+            let score = self
+                .coll_scores
+                .find_one_and_update(
+                    doc! {
+                        "sentence": vote.sentence
+                    },
+                    doc! {
+                        "$inc": {"score": -vote.vote}
+                    },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await?;
+            if let Some(score) = score {
+                self.stats
+                    .write()
+                    .scores
+                    .insert(score.sentence, score.score);
+            }
+        }
+        self.coll_votes
+            .delete_many(doc! {"user": user}, None)
+            .await?;
+        self.coll_users
+            .delete_one(doc! {"user": user}, None)
+            .await?;
 
-        let mut stats = self.stats.write();
-        stats.total = total.total;
-        stats.sentences.insert(sentence.sentence, sentence.count);
-        stats.users.insert(user.user, user.count);
+        self.stats.write().users.remove(user);
+        self.masker.forget(user);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn votes_weigh_in_popularity() {
+        let stats = Stat {
+            total: 5,
+            sentences: HashMap::from([(String::from("卖出"), 3), (String::from("差评"), 2)]),
+            users: HashMap::new(),
+            media: HashMap::new(),
+            scores: HashMap::from([
+                (String::from("差评"), -1),
+                (String::from("好评"), 2),
+                (String::from("卖出"), 0),
+            ]),
+        };
+        assert!((stats.popularity("卖出") - 3.0).abs() < f64::EPSILON);
+        assert!((stats.popularity("好评") - 2.0 * VOTE_WEIGHT).abs() < f64::EPSILON);
+        // never negative, so that it can weigh a choice
+        assert!(stats.popularity("差评").abs() < f64::EPSILON);
+        assert!(stats.popularity("无人问津").abs() < f64::EPSILON);
+    }
+}
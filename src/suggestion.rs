@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
+use mongodb::{Collection, Database};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::corpus::{CommonFormat, Corpus, Entry};
+use crate::errors::Result;
+
+pub const MAX_SUGGESTION_CHARS: usize = 200;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub text: String,
+    pub submitter: String,
+    pub status: Status,
+}
+
+async fn fetch_approved(coll: &Collection<Suggestion>) -> Result<Vec<Entry>> {
+    Ok(coll
+        .find(doc! {"status": "approved"}, None)
+        .await?
+        .map_ok(|suggestion| Entry::plain(suggestion.text))
+        .try_collect()
+        .await?)
+}
+
+/// User-submitted sentences and the overlay corpus of approved ones.
+#[derive(Debug)]
+pub struct SuggestionQueue {
+    coll: Collection<Suggestion>,
+    approved: RwLock<Vec<Entry>>,
+    // suggestions being edited, keyed by admin user id
+    editing: Mutex<HashMap<i64, ObjectId>>,
+}
+
+impl SuggestionQueue {
+    pub async fn new(db: &Database) -> Result<Self> {
+        let coll = db.collection("suggestions");
+        let approved = fetch_approved(&coll).await?;
+        Ok(Self {
+            coll,
+            approved: RwLock::new(approved),
+            editing: Mutex::new(HashMap::new()),
+        })
+    }
+    /// No approved sentences, without reading `db`.
+    #[cfg(test)]
+    pub fn empty(db: &Database) -> Self {
+        Self {
+            coll: db.collection("suggestions"),
+            approved: RwLock::default(),
+            editing: Mutex::default(),
+        }
+    }
+    /// Approved sentences, to be merged with the remote corpus.
+    pub fn approved(&self) -> RwLockReadGuard<Vec<Entry>> {
+        self.approved.read()
+    }
+    pub fn set_editing(&self, user: i64, id: ObjectId) {
+        self.editing.lock().insert(user, id);
+    }
+    pub fn take_editing(&self, user: i64) -> Option<ObjectId> {
+        self.editing.lock().remove(&user)
+    }
+    /// Queue `text` for review.
+    ///
+    /// Returns `false` if the same text is already pending.
+    pub async fn submit(&self, text: String, submitter: String) -> Result<bool> {
+        let result = self
+            .coll
+            .update_one(
+                doc! {"text": text, "status": "pending"},
+                doc! {"$setOnInsert": {"submitter": submitter}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(result.upserted_id.is_some())
+    }
+    pub async fn pending(&self, limit: i64) -> Result<Vec<Suggestion>> {
+        Ok(self
+            .coll
+            .find(
+                doc! {"status": "pending"},
+                FindOptions::builder().limit(limit).build(),
+            )
+            .await?
+            .try_collect()
+            .await?)
+    }
+    /// Approve a pending suggestion, optionally replacing its text.
+    ///
+    /// Returns `None` if there's no such pending suggestion.
+    pub async fn approve(&self, id: ObjectId, text: Option<String>) -> Result<Option<Suggestion>> {
+        let update = text.map_or_else(
+            || doc! {"$set": {"status": "approved"}},
+            |text| doc! {"$set": {"status": "approved", "text": text}},
+        );
+        let suggestion = self
+            .coll
+            .find_one_and_update(
+                doc! {"_id": id, "status": "pending"},
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+        if let Some(suggestion) = &suggestion {
+            self.approved
+                .write()
+                .push(Entry::plain(suggestion.text.clone()));
+        }
+        Ok(suggestion)
+    }
+    /// Drop the pending suggestions of `submitter`, and anonymise the reviewed ones, which
+    /// live on in the corpus or are never shown again.
+    pub async fn forget(&self, submitter: &str) -> Result<()> {
+        self.coll
+            .delete_many(doc! {"submitter": submitter, "status": "pending"}, None)
+            .await?;
+        self.coll
+            .update_many(
+                doc! {"submitter": submitter},
+                doc! {"$set": {"submitter": ""}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+    /// Reject a pending suggestion. Returns `None` if there's no such pending suggestion.
+    pub async fn reject(&self, id: ObjectId) -> Result<Option<Suggestion>> {
+        Ok(self
+            .coll
+            .find_one_and_update(
+                doc! {"_id": id, "status": "pending"},
+                doc! {"$set": {"status": "rejected"}},
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?)
+    }
+}
+
+/// Render approved sentences missing from the common sentences of `corpus` as a unified diff
+/// appending to its file, as JSON lines if it's `common.jsonl`.
+pub fn export_patch(corpus: &Corpus, approved: &[Entry]) -> Option<String> {
+    let added: Vec<_> = approved
+        .iter()
+        .filter(|entry| !corpus.common.iter().any(|e| e.text == entry.text))
+        .collect();
+    if added.is_empty() {
+        return None;
+    }
+    let file = corpus.format.file_name();
+    let mut patch = format!(
+        "--- a/{}\n+++ b/{}\n@@ -{},0 +{},{} @@\n",
+        file,
+        file,
+        corpus.lines,
+        corpus.lines + 1,
+        added.len()
+    );
+    for entry in added {
+        patch.push('+');
+        match corpus.format {
+            CommonFormat::Plain => patch.push_str(&entry.text),
+            CommonFormat::Tagged => patch.push_str(&json!({ "text": entry.text }).to_string()),
+        }
+        patch.push('\n');
+    }
+    Some(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::CorpusClient;
+    use crate::testing::{FakeApi, CORPUS};
+
+    #[tokio::test]
+    async fn approved_sentences_are_exported_in_corpus_format() {
+        let approved = [
+            Entry::plain(String::from("我好菜啊")),
+            Entry::plain(String::from("\"新\"句")),
+        ];
+        let api = FakeApi::start(&CORPUS);
+        let corpus = CorpusClient::new_with_url(&api.corpus_url()).await.unwrap();
+        assert_eq!(
+            export_patch(&corpus.corpus(), &approved).unwrap(),
+            "--- a/common.txt\n+++ b/common.txt\n@@ -3,0 +4,1 @@\n+\"新\"句\n"
+        );
+
+        let mut tagged = CORPUS.to_vec();
+        tagged.push(("common.jsonl", r#"{"text": "我好菜啊", "tags": ["自嘲"]}"#));
+        let api = FakeApi::start(&tagged);
+        let corpus = CorpusClient::new_with_url(&api.corpus_url()).await.unwrap();
+        assert_eq!(
+            export_patch(&corpus.corpus(), &approved).unwrap(),
+            "--- a/common.jsonl\n+++ b/common.jsonl\n@@ -1,0 +2,1 @@\n+{\"text\":\"\\\"新\\\"句\"}\n"
+        );
+        assert!(export_patch(&corpus.corpus(), &approved[..1]).is_none());
+
+        // blank lines are skipped by the corpus, but counted in the hunk
+        let mut spaced = CORPUS.to_vec();
+        spaced[0] = ("common.txt", "我好菜啊\n\n我是废物\n");
+        let api = FakeApi::start(&spaced);
+        let corpus = CorpusClient::new_with_url(&api.corpus_url()).await.unwrap();
+        assert_eq!(corpus.corpus().common.len(), 2);
+        assert!(export_patch(&corpus.corpus(), &approved)
+            .unwrap()
+            .contains("@@ -3,0 +4,1 @@"));
+    }
+}
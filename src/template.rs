@@ -0,0 +1,121 @@
+const DELIMITER: char = '|';
+
+enum Token<'a> {
+    Text(&'a str),
+    Slot(&'a str),
+}
+
+fn is_slot_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn tokenize(template: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) if is_slot_name(&after[..end]) => {
+                tokens.push(Token::Text(&rest[..start]));
+                tokens.push(Token::Slot(&after[..end]));
+                rest = &after[end + 1..];
+            }
+            _ => {
+                tokens.push(Token::Text(&rest[..=start]));
+                rest = after;
+            }
+        }
+    }
+    tokens.push(Token::Text(rest));
+    tokens
+}
+
+/// Split an inline query into the search keyword and the arguments after `|`.
+pub fn split_query(query: &str) -> (&str, Vec<&str>) {
+    let mut parts = query.split(DELIMITER);
+    let keyword = parts.next().unwrap_or_default().trim();
+    let args = parts.map(str::trim).filter(|s| !s.is_empty()).collect();
+    (keyword, args)
+}
+
+/// Distinct placeholder names of a template, in order of first appearance.
+pub fn slots(template: &str) -> Vec<&str> {
+    let mut slots = vec![];
+    for token in tokenize(template) {
+        if let Token::Slot(name) = token {
+            if !slots.contains(&name) {
+                slots.push(name);
+            }
+        }
+    }
+    slots
+}
+
+/// Fill placeholders positionally with `args`.
+///
+/// Returns `None` if there are more distinct placeholders than arguments.
+pub fn render(template: &str, args: &[&str]) -> Option<String> {
+    let slots = slots(template);
+    if slots.len() > args.len() {
+        return None;
+    }
+    Some(
+        tokenize(template)
+            .into_iter()
+            .map(|token| match token {
+                Token::Text(s) => s,
+                Token::Slot(name) => {
+                    let idx = slots.iter().position(|slot| *slot == name).unwrap();
+                    args[idx]
+                }
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_named_once_in_order() {
+        assert_eq!(
+            slots("{name}好菜，{target}也是{name}"),
+            vec!["name", "target"]
+        );
+        assert_eq!(slots("{名字}"), vec!["名字"]);
+        // not slot names
+        assert!(slots("{} { name} {a-b}").is_empty());
+    }
+
+    #[test]
+    fn unterminated_and_nested_braces_are_text() {
+        assert!(slots("{name").is_empty());
+        assert_eq!(render("{name", &["张三"]).unwrap(), "{name");
+        assert_eq!(slots("{{name}}"), vec!["name"]);
+        assert_eq!(render("{{name}}", &["张三"]).unwrap(), "{张三}");
+    }
+
+    #[test]
+    fn adjacent_slots_are_filled_positionally() {
+        assert_eq!(render("{a}{b}{a}", &["1", "2", "3"]).unwrap(), "121");
+        assert_eq!(render("{a}{b}", &["1"]), None);
+        assert_eq!(render("没有占位符", &[]).unwrap(), "没有占位符");
+    }
+
+    #[test]
+    fn arguments_are_not_expanded() {
+        let args = ["{b}", "<&_*|>"];
+        assert_eq!(render("{a}{b}", &args).unwrap(), "{b}<&_*|>");
+    }
+
+    #[test]
+    fn queries_split_at_bars() {
+        assert_eq!(
+            split_query(" 猫 | 张三 || 李四 "),
+            ("猫", vec!["张三", "李四"])
+        );
+        assert_eq!(split_query("猫"), ("猫", vec![]));
+        assert_eq!(split_query("|张三"), ("", vec!["张三"]));
+    }
+}
@@ -0,0 +1,260 @@
+//! An in-process fake of the Telegram Bot API for end-to-end tests.
+//!
+//! Handlers run in a real [`Dispatcher`](teloxide::dispatching2::Dispatcher) polling the fake
+//! server, which serves injected updates, records every method call, and serves a local corpus.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::{Json, Router};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use teloxide::adaptors::AutoSend;
+use teloxide::requests::RequesterExt;
+use teloxide::Bot;
+use tokio::sync::Notify;
+use url::Url;
+
+pub const BOT_ID: i64 = 10000;
+/// A plain corpus of three sentences.
+pub const CORPUS: [(&str, &str); 4] = [
+    ("common.txt", "我好菜啊\n我是废物\n又被大佬带飞了"),
+    ("refuse.txt", "不要"),
+    ("trigger.txt", "菜"),
+    ("phrase.txt", "我 好菜"),
+];
+const TOKEN: &str = "10000:TEST";
+// How long `getUpdates` waits for an update before answering with none.
+const POLL_TIMEOUT: Duration = Duration::from_millis(200);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct State {
+    calls: Mutex<Vec<(String, Value)>>,
+    updates: Mutex<VecDeque<Value>>,
+    update_arrived: Notify,
+    corpus: HashMap<String, String>,
+    missing_chats: Mutex<HashSet<i64>>,
+    next_id: AtomicI64,
+}
+
+impl State {
+    fn next_id(&self) -> i64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    async fn take_updates(&self) -> Vec<Value> {
+        if self.updates.lock().is_empty() {
+            let _ = tokio::time::timeout(POLL_TIMEOUT, self.update_arrived.notified()).await;
+        }
+        self.updates.lock().drain(..).collect()
+    }
+}
+
+fn user(id: i64) -> Value {
+    json!({"id": id, "is_bot": false, "first_name": format!("user{}", id), "language_code": "zh-hans"})
+}
+
+fn private_chat(id: i64) -> Value {
+    json!({"id": id, "type": "private", "first_name": format!("user{}", id)})
+}
+
+// Answer a method call the way Telegram would, as far as the handlers care.
+async fn method(
+    Path((_, method)): Path<(String, String)>,
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Json<Value> {
+    let payload: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    // teloxide sends `SendMessage` etc., method names being case-insensitive
+    let mut chars = method.chars();
+    let method: String = chars
+        .next()
+        .map(|c| c.to_ascii_lowercase())
+        .into_iter()
+        .chain(chars)
+        .collect();
+    let chat = payload["chat_id"]
+        .as_i64()
+        .or_else(|| payload["scope"]["chat_id"].as_i64());
+    if chat.is_some_and(|chat| state.missing_chats.lock().contains(&chat)) {
+        state.calls.lock().push((method, payload));
+        return Json(json!({
+            "ok": false,
+            "error_code": 400,
+            "description": "Bad Request: chat not found"
+        }));
+    }
+    let result = match method.as_str() {
+        "getMe" => json!({
+            "id": BOT_ID,
+            "is_bot": true,
+            "first_name": "chi",
+            "username": "chi_bot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": true
+        }),
+        "getWebhookInfo" => json!({
+            "url": "",
+            "has_custom_certificate": false,
+            "pending_update_count": 0
+        }),
+        "getUpdates" => return Json(json!({"ok": true, "result": state.take_updates().await})),
+        "sendMessage" | "editMessageText" => json!({
+            "message_id": state.next_id(),
+            "date": 0,
+            "chat": private_chat(payload["chat_id"].as_i64().unwrap_or_default()),
+            "from": {"id": BOT_ID, "is_bot": true, "first_name": "chi"},
+            "text": payload["text"]
+        }),
+        _ => json!(true),
+    };
+    state.calls.lock().push((method, payload));
+    Json(json!({"ok": true, "result": result}))
+}
+
+async fn corpus_file(
+    Path((_, file)): Path<(String, String)>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<String, StatusCode> {
+    state
+        .corpus
+        .get(&file)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// The fake Bot API server, also serving corpus files under `/corpus/`.
+#[derive(Debug, Clone)]
+pub struct FakeApi {
+    url: Url,
+    state: Arc<State>,
+}
+
+impl FakeApi {
+    pub fn start(corpus: &[(&str, &str)]) -> Self {
+        let state = Arc::new(State {
+            corpus: corpus
+                .iter()
+                .map(|(name, content)| ((*name).to_string(), (*content).to_string()))
+                .collect(),
+            ..State::default()
+        });
+        let app = Router::new()
+            // `/bot<token>/<method>` and `/corpus/<file>`
+            .route("/:prefix/:name", post(method).get(corpus_file))
+            .layer(Extension(state.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind fake api");
+        let url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .expect("unable to serve fake api")
+                .serve(app.into_make_service()),
+        );
+        Self { url, state }
+    }
+    /// A bot talking to this server.
+    pub fn bot(&self) -> AutoSend<Bot> {
+        Bot::new(TOKEN).set_api_url(self.url.clone()).auto_send()
+    }
+    pub fn corpus_url(&self) -> Url {
+        self.url.join("corpus/").unwrap()
+    }
+    /// Fail calls concerning chat `id` as if the bot had never met it.
+    pub fn remove_chat(&self, id: i64) {
+        self.state.missing_chats.lock().insert(id);
+    }
+    fn inject(&self, kind: &str, content: Value) {
+        let mut update = json!({"update_id": self.state.next_id()});
+        update[kind] = content;
+        self.state.updates.lock().push_back(update);
+        self.state.update_arrived.notify_one();
+    }
+    pub fn inject_inline_query(&self, user_id: i64, query: &str) -> String {
+        let id = self.state.next_id().to_string();
+        self.inject(
+            "inline_query",
+            json!({
+                "id": id,
+                "from": user(user_id),
+                "query": query,
+                "offset": "",
+                "chat_type": "sender"
+            }),
+        );
+        id
+    }
+    pub fn inject_chosen_inline_result(&self, user_id: i64, result_id: &str, query: &str) {
+        self.inject(
+            "chosen_inline_result",
+            json!({"result_id": result_id, "from": user(user_id), "query": query}),
+        );
+    }
+    pub fn inject_message(&self, user_id: i64, text: &str) {
+        let mut message = json!({
+            "message_id": self.state.next_id(),
+            "date": 0,
+            "chat": private_chat(user_id),
+            "from": user(user_id),
+            "text": text
+        });
+        if text.starts_with('/') {
+            let length = text.split_whitespace().next().unwrap_or_default().len();
+            message["entities"] = json!([{"type": "bot_command", "offset": 0, "length": length}]);
+        }
+        self.inject("message", message);
+    }
+    /// Payloads of the calls to `method` so far.
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.state
+            .calls
+            .lock()
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+    /// Wait for a call to `method` satisfying `pred`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there's none in time.
+    pub async fn wait_for(&self, method: &str, pred: impl Fn(&Value) -> bool) -> Value {
+        tokio::time::timeout(WAIT_TIMEOUT, async {
+            loop {
+                if let Some(payload) = self.calls(method).into_iter().find(|p| pred(p)) {
+                    return payload;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("no matching call to {}", method))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::requests::Requester;
+
+    use super::*;
+
+    const USER: i64 = 42;
+
+    #[tokio::test]
+    async fn fake_api_records_calls() {
+        let api = FakeApi::start(&[]);
+        let msg = api.bot().send_message(USER, "hello").await.unwrap();
+        assert_eq!(msg.text(), Some("hello"));
+        assert_eq!(api.calls("sendMessage")[0]["chat_id"], USER);
+    }
+}
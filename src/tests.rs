@@ -0,0 +1,350 @@
+//! End-to-end tests of the handlers against [`FakeApi`].
+//!
+//! Handlers which only read the stores run against empty ones. Tests writing to them need a
+//! MongoDB server at `APP_TEST_MONGODB_URI`, and are ignored unless run with
+//! `--include-ignored`. Each of them works in a fresh database dropped afterwards.
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mongodb::{Client, Database};
+use parking_lot::RwLock;
+use serde_json::Value;
+use teloxide::dispatching::update_listeners;
+use teloxide::dispatching::ShutdownToken;
+use teloxide::dispatching2::Dispatcher;
+use teloxide::dptree;
+use teloxide::error_handlers::LoggingErrorHandler;
+use teloxide::requests::Requester;
+
+use crate::alert::alerts;
+use crate::cache::Caching;
+use crate::jobs::Scheduler;
+use crate::markup::Format;
+use crate::ratelimit::Quota;
+use crate::render::{Buttons, Renderer};
+use crate::seller::{MoanGrammar, Selection};
+use crate::testing::{FakeApi, CORPUS};
+use crate::{
+    schema, Admins, Booking, CorpusClient, Favorites, History, Leaderboard, Limits, MediaCatalog,
+    MongoDBLogger, Seller, SuggestionQueue,
+};
+
+const USER: i64 = 42;
+
+struct Harness {
+    api: FakeApi,
+    // the database to drop, unless the stores never touch it
+    db: Option<Database>,
+    logger: Arc<MongoDBLogger>,
+    shutdown: ShutdownToken,
+}
+
+impl Harness {
+    // Run the dispatcher against a fresh fake API and database.
+    async fn start() -> Self {
+        let uri = env::var("APP_TEST_MONGODB_URI").expect("APP_TEST_MONGODB_URI not set");
+        let db = Client::with_uri_str(uri)
+            .await
+            .unwrap()
+            .database(&format!("chi_test_{:x}", rand::random::<u64>()));
+        let harness = Self::run(
+            Arc::new(MediaCatalog::new(&db).await.unwrap()),
+            Arc::new(SuggestionQueue::new(&db).await.unwrap()),
+            Arc::new(Favorites::new(&db).await.unwrap()),
+            Arc::new(Leaderboard::new(&db).await.unwrap()),
+            Arc::new(MongoDBLogger::new(db.clone(), None).await.unwrap()),
+        )
+        .await;
+        Self {
+            db: Some(db),
+            ..harness
+        }
+    }
+    // Run the dispatcher against a fresh fake API and empty stores, which fail if written to.
+    async fn start_empty() -> Self {
+        // the driver doesn't connect until the first operation
+        let db = Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("chi_test");
+        Self::run(
+            Arc::new(MediaCatalog::empty(&db)),
+            Arc::new(SuggestionQueue::empty(&db)),
+            Arc::new(Favorites::empty(&db)),
+            Arc::new(Leaderboard::empty(&db)),
+            Arc::new(MongoDBLogger::empty(&db)),
+        )
+        .await
+    }
+    async fn run(
+        catalog: Arc<MediaCatalog>,
+        suggestions: Arc<SuggestionQueue>,
+        favorites: Arc<Favorites>,
+        leaderboard: Arc<Leaderboard>,
+        logger: Arc<MongoDBLogger>,
+    ) -> Self {
+        let api = FakeApi::start(&CORPUS);
+        let corpus = Arc::new(CorpusClient::new_with_url(&api.corpus_url()).await.unwrap());
+        let seller = Arc::new(Seller::new(
+            corpus.clone(),
+            catalog.clone(),
+            suggestions.clone(),
+            Selection::Uniform,
+            MoanGrammar::default(),
+        ));
+        let renderer = Arc::new(Renderer::new(Format::Html, None, Buttons::default()));
+        let quota = Quota {
+            rate: 100.0,
+            burst: 100.0,
+        };
+
+        let bot = api.bot();
+        let mut dispatcher = Dispatcher::builder(bot.clone(), schema())
+            .dependencies(dptree::deps![
+                corpus,
+                seller,
+                renderer,
+                logger.clone(),
+                Arc::new(History::new(10)),
+                favorites,
+                Arc::new(RwLock::new(Booking::new(Duration::ZERO))),
+                Arc::new(Admins::default()),
+                catalog,
+                suggestions,
+                leaderboard,
+                Arc::new(Limits::new(quota, quota, 25)),
+                Caching::default(),
+                Arc::new(Scheduler::default()),
+                alerts(None, Duration::ZERO, Duration::ZERO).1,
+                bot.get_me().await.unwrap()
+            ])
+            .build();
+        let shutdown = dispatcher.shutdown_token();
+        tokio::spawn(async move {
+            // without a polling timeout, shutdown is noticed within a second
+            let listener = update_listeners::polling(bot, None, None, None);
+            dispatcher
+                .dispatch_with_listener(listener, LoggingErrorHandler::new())
+                .await;
+        });
+
+        Self {
+            api,
+            db: None,
+            logger,
+            shutdown,
+        }
+    }
+    async fn stop(self) {
+        if let Ok(done) = self.shutdown.shutdown() {
+            done.await;
+        }
+        if let Some(db) = self.db {
+            db.drop(None).await.unwrap();
+        }
+    }
+    // Wait until `pred` holds for the stats.
+    async fn wait_stats(&self, pred: impl Fn(&crate::stats::Stat) -> bool) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !pred(&self.logger.stats()) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("stats not updated in time");
+    }
+}
+
+fn message_text(result: &Value) -> Option<&str> {
+    result["input_message_content"]["message_text"].as_str()
+}
+
+#[tokio::test]
+async fn inline_query_is_answered_from_corpus() {
+    let harness = Harness::start_empty().await;
+
+    let id = harness.api.inject_inline_query(USER, "菜");
+    let answer = harness
+        .api
+        .wait_for("answerInlineQuery", |p| p["inline_query_id"] == id.as_str())
+        .await;
+    let texts: Vec<_> = answer["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(message_text)
+        .collect();
+    assert!(texts.contains(&"我好菜啊"));
+    assert!(!texts.contains(&"我是废物"));
+    // the personal stat card comes last
+    assert_eq!(texts.last(), Some(&"我已经卖了 0 句菜"));
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB"]
+async fn chosen_result_is_logged() {
+    let harness = Harness::start().await;
+
+    let id = harness.api.inject_inline_query(USER, "废物");
+    let answer = harness
+        .api
+        .wait_for("answerInlineQuery", |p| p["inline_query_id"] == id.as_str())
+        .await;
+    let result = answer["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|result| message_text(result) == Some("我是废物"))
+        .expect("sentence not offered");
+    harness
+        .api
+        .inject_chosen_inline_result(USER, result["id"].as_str().unwrap(), "废物");
+    harness
+        .wait_stats(|stats| stats.sentences.get("我是废物") == Some(&1))
+        .await;
+    {
+        let stats = harness.logger.stats();
+        assert_eq!(stats.total, 1);
+        assert_eq!(stats.users.values().sum::<u64>(), 1);
+    }
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB"]
+async fn stat_card_is_not_logged() {
+    let harness = Harness::start().await;
+
+    let id = harness.api.inject_inline_query(USER, "");
+    let answer = harness
+        .api
+        .wait_for("answerInlineQuery", |p| p["inline_query_id"] == id.as_str())
+        .await;
+    let card = answer["results"]
+        .as_array()
+        .unwrap()
+        .last()
+        .unwrap()
+        .clone();
+    harness
+        .api
+        .inject_chosen_inline_result(USER, card["id"].as_str().unwrap(), "");
+    // a moan is logged afterwards, so the card must have been skipped before it
+    let moan = &answer["results"][0];
+    harness
+        .api
+        .inject_chosen_inline_result(USER, moan["id"].as_str().unwrap(), "");
+    harness.wait_stats(|stats| stats.total > 0).await;
+    assert_eq!(harness.logger.stats().total, 1);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn commands_are_answered() {
+    let harness = Harness::start_empty().await;
+
+    harness.api.inject_message(USER, "/stat");
+    harness
+        .api
+        .wait_for("sendMessage", |p| {
+            p["text"]
+                .as_str()
+                .unwrap_or_default()
+                .starts_with("总共已经有 0 名迟化人")
+        })
+        .await;
+
+    harness.api.inject_message(USER, "/start");
+    harness
+        .api
+        .wait_for("sendMessage", |p| {
+            p["text"]
+                .as_str()
+                .unwrap_or_default()
+                .starts_with("在任意聊天窗口输入 @chi_bot")
+        })
+        .await;
+    harness.api.inject_message(USER, "/help");
+    harness
+        .api
+        .wait_for("sendMessage", |p| {
+            p["text"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("/forgetme - 删除我的全部数据")
+        })
+        .await;
+    harness.api.inject_message(USER, "/about");
+    harness
+        .api
+        .wait_for("sendMessage", |p| {
+            p["text"].as_str().unwrap_or_default().contains("共 3 句")
+        })
+        .await;
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB"]
+async fn favorites_are_kept() {
+    let harness = Harness::start().await;
+
+    harness.api.inject_message(USER, "/fav add 我好菜啊");
+    harness
+        .api
+        .wait_for("sendMessage", |p| p["text"] == "已收藏")
+        .await;
+    harness.api.inject_message(USER, "/fav");
+    harness
+        .api
+        .wait_for("sendMessage", |p| p["text"] == "1. 我好菜啊")
+        .await;
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn malformed_suggestions_are_refused() {
+    let harness = Harness::start_empty().await;
+
+    harness.api.inject_message(USER, "/suggest 第一行\n第二行");
+    harness
+        .api
+        .wait_for("sendMessage", |p| p["text"] == "投稿只能有一行")
+        .await;
+    harness
+        .api
+        .inject_message(USER, &format!("/suggest {}", "菜".repeat(201)));
+    harness
+        .api
+        .wait_for("sendMessage", |p| p["text"] == "投稿不能超过 200 个字")
+        .await;
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB"]
+async fn pending_suggestions_are_deduplicated() {
+    let harness = Harness::start().await;
+
+    harness.api.inject_message(USER, "/suggest 新句");
+    harness
+        .api
+        .wait_for("sendMessage", |p| p["text"] == "投稿已收到，等待管理员审核")
+        .await;
+    harness.api.inject_message(USER, "/suggest 新句");
+    harness
+        .api
+        .wait_for("sendMessage", |p| p["text"] == "这句话已经在等待审核了")
+        .await;
+
+    harness.stop().await;
+}
@@ -1,4 +1,75 @@
+use std::collections::HashSet;
+
+use hmac::{Hmac, Mac};
+use parking_lot::RwLock;
+use sha2::Sha256;
+
+/// Length of a legacy (md5) user hash, as opposed to a keyed (HMAC-SHA256) one.
+pub const LEGACY_HASH_LEN: usize = 32;
+
+/// The unsalted md5 hash used before masking was keyed.
 #[allow(clippy::cast_sign_loss)]
-pub fn mask_user(user: i64) -> String {
+pub fn legacy_mask_user(user: i64) -> String {
     format!("{:x}", md5::compute((user as u128).to_le_bytes()))
 }
+
+/// The HMAC-SHA256 hash of `user` keyed by `key`.
+pub fn keyed_mask_user(key: &[u8], user: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&user.to_le_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Masks users of a bot into the hashes its records are stored under.
+///
+/// Users are masked by the keyed hash if there's a key, except legacy users still recorded
+/// under their legacy hash, waiting for migration.
+#[derive(Debug, Default)]
+pub struct Masker {
+    key: Option<Vec<u8>>,
+    legacy: RwLock<HashSet<String>>,
+}
+
+impl Masker {
+    pub fn new(key: Option<Vec<u8>>, legacy: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            key,
+            legacy: RwLock::new(legacy.into_iter().collect()),
+        }
+    }
+    pub fn mask(&self, user: i64) -> String {
+        let legacy = legacy_mask_user(user);
+        match &self.key {
+            Some(key) if !self.legacy.read().contains(&legacy) => keyed_mask_user(key, user),
+            _ => legacy,
+        }
+    }
+    /// Mask a legacy user by the keyed hash from now on, once their records are gone.
+    pub fn forget(&self, hash: &str) {
+        self.legacy.write().remove(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_users_keep_their_hash_until_forgotten() {
+        let key = b"key".to_vec();
+        let masker = Masker::new(Some(key.clone()), [legacy_mask_user(1)]);
+        assert_eq!(masker.mask(1), legacy_mask_user(1));
+        assert_eq!(masker.mask(2), keyed_mask_user(&key, 2));
+        masker.forget(&legacy_mask_user(1));
+        assert_eq!(masker.mask(1), keyed_mask_user(&key, 1));
+    }
+
+    #[test]
+    fn users_are_masked_by_legacy_hash_without_key() {
+        assert_eq!(Masker::new(None, []).mask(1), legacy_mask_user(1));
+    }
+}